@@ -0,0 +1,411 @@
+//! Dithering stages that run between computing a palette and mapping each
+//! frame's pixels onto it.
+
+use super::{Image, RGBA};
+
+/// A dithering strategy used when mapping pixels onto the palette.
+///
+/// `None` maps every pixel straight to its closest palette color.
+///
+/// `FloydSteinberg`, `Atkinson` and `Sierra` are error diffusion kernels.
+/// The difference between a pixel's color and the palette color it was
+/// mapped to is spread onto its unmapped neighbors, which trades banding in
+/// gradients for fine grain. Floyd-Steinberg spreads the whole error over
+/// four neighbors, Sierra spreads it over ten for a smoother result, and
+/// Atkinson only spreads 3/4 of it, which keeps more contrast at the cost of
+/// losing detail in highlights and shadows.
+///
+/// `Bayer2`, `Bayer4`, `Bayer8` and `BlueNoise` are ordered dithers. Each
+/// pixel is nudged by a threshold that only depends on its position, so
+/// unchanging areas of consecutive frames map to the same palette indices
+/// and don't shimmer the way error diffusion does in animations. The Bayer
+/// matrices tile a regular crosshatch pattern of the given size, while the
+/// blue noise map spreads its thresholds evenly without a visible grid.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Dither {
+    None,
+    FloydSteinberg,
+    Atkinson,
+    Sierra,
+    Bayer2,
+    Bayer4,
+    Bayer8,
+    BlueNoise,
+}
+
+/// Neighbor offsets `(dx, dy)` and their share of the error, followed by the
+/// divisor of the shares.
+type Kernel = (&'static [(isize, usize, f32)], f32);
+
+const FLOYD_STEINBERG: Kernel = (&[
+    (1, 0, 7.0),
+    (-1, 1, 3.0), (0, 1, 5.0), (1, 1, 1.0),
+], 16.0);
+
+const ATKINSON: Kernel = (&[
+    (1, 0, 1.0), (2, 0, 1.0),
+    (-1, 1, 1.0), (0, 1, 1.0), (1, 1, 1.0),
+    (0, 2, 1.0),
+], 8.0);
+
+const SIERRA: Kernel = (&[
+    (1, 0, 5.0), (2, 0, 3.0),
+    (-2, 1, 2.0), (-1, 1, 4.0), (0, 1, 5.0), (1, 1, 4.0), (2, 1, 2.0),
+    (-1, 2, 2.0), (0, 2, 3.0), (1, 2, 2.0),
+], 32.0);
+
+impl Dither {
+    fn kernel(&self) -> Option<Kernel> {
+        match *self {
+            Dither::None => None,
+            Dither::FloydSteinberg => Some(FLOYD_STEINBERG),
+            Dither::Atkinson => Some(ATKINSON),
+            Dither::Sierra => Some(SIERRA),
+            _ => None,
+        }
+    }
+}
+
+/// Side length of the generated blue noise threshold map.
+const BLUE_NOISE_SIZE: usize = 32;
+
+/// A square matrix of thresholds between 0.0 and 1.0 that's tiled over an
+/// image for ordered dithering.
+#[derive(Debug, Clone)]
+pub struct ThresholdMap {
+    size: usize,
+    thresholds: Vec<f32>,
+}
+
+impl ThresholdMap {
+    /// Builds the threshold map of an ordered dither, or `None` for any other
+    /// kind of dither.
+    pub fn new(dither: Dither) -> Option<ThresholdMap> {
+        let (size, ranks) = match dither {
+            Dither::Bayer2 => (2, bayer_ranks(2)),
+            Dither::Bayer4 => (4, bayer_ranks(4)),
+            Dither::Bayer8 => (8, bayer_ranks(8)),
+            Dither::BlueNoise => (BLUE_NOISE_SIZE, blue_noise_ranks(BLUE_NOISE_SIZE)),
+            _ => return None,
+        };
+        let len = ranks.len() as f32;
+        Some(ThresholdMap {
+            size,
+            thresholds: ranks.into_iter().map(|rank| (rank as f32 + 0.5) / len).collect(),
+        })
+    }
+
+    fn threshold(&self, x: usize, y: usize) -> f32 {
+        self.thresholds[(y % self.size) * self.size + x % self.size]
+    }
+}
+
+/// Ranks of a `size` x `size` Bayer matrix, built by recursively tiling the
+/// 2x2 matrix.
+fn bayer_ranks(size: usize) -> Vec<usize> {
+    let mut ranks = vec![0];
+    let mut n = 1;
+    while n < size {
+        let mut next = vec![0; n * n * 4];
+        for y in 0..n {
+            for x in 0..n {
+                let rank = ranks[y * n + x] * 4;
+                next[y * n * 2 + x] = rank;
+                next[y * n * 2 + x + n] = rank + 2;
+                next[(y + n) * n * 2 + x] = rank + 3;
+                next[(y + n) * n * 2 + x + n] = rank + 1;
+            }
+        }
+        ranks = next;
+        n *= 2;
+    }
+    ranks
+}
+
+/// Ranks of a `size` x `size` blue noise matrix, generated with Ulichney's
+/// void-and-cluster method.
+///
+/// A sparse, evenly relaxed pattern of points is ranked by repeatedly
+/// removing the point in the tightest cluster, then the remaining cells are
+/// ranked by repeatedly filling the largest void. Clusters and voids are found
+/// with a gaussian energy field that wraps around the edges, so the map tiles
+/// seamlessly.
+fn blue_noise_ranks(size: usize) -> Vec<usize> {
+    let len = size * size;
+    let sigma = 1.5f32;
+    let mut falloff = vec![0f32; len];
+    for dy in 0..size {
+        for dx in 0..size {
+            let wx = dx.min(size - dx) as f32;
+            let wy = dy.min(size - dy) as f32;
+            falloff[dy * size + dx] = (-(wx * wx + wy * wy) / (2.0 * sigma * sigma)).exp();
+        }
+    }
+
+    let update = |energy: &mut [f32], at: usize, sign: f32| {
+        let (ax, ay) = (at % size, at / size);
+        for y in 0..size {
+            for x in 0..size {
+                let dx = (x + size - ax) % size;
+                let dy = (y + size - ay) % size;
+                energy[y * size + x] += sign * falloff[dy * size + dx];
+            }
+        }
+    };
+    let tightest_cluster = |points: &[bool], energy: &[f32]| {
+        (0..len).filter(|&n| points[n])
+            .fold((0, f32::NEG_INFINITY), |best, n| if energy[n] > best.1 { (n, energy[n]) } else { best }).0
+    };
+    let largest_void = |points: &[bool], energy: &[f32]| {
+        (0..len).filter(|&n| !points[n])
+            .fold((0, f32::INFINITY), |best, n| if energy[n] < best.1 { (n, energy[n]) } else { best }).0
+    };
+
+    // Seed a tenth of the cells with a fixed xorshift sequence so the map is
+    // the same every time.
+    let mut points = vec![false; len];
+    let mut energy = vec![0f32; len];
+    let mut state = 0x2545_f491u32;
+    let mut seeded = 0;
+    while seeded < len / 10 {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        let n = state as usize % len;
+        if !points[n] {
+            points[n] = true;
+            update(&mut energy, n, 1.0);
+            seeded += 1;
+        }
+    }
+
+    // Relax the seed by moving points from clusters into voids until the
+    // point that's removed is the one that gets put back.
+    loop {
+        let cluster = tightest_cluster(&points, &energy);
+        points[cluster] = false;
+        update(&mut energy, cluster, -1.0);
+        let void = largest_void(&points, &energy);
+        points[void] = true;
+        update(&mut energy, void, 1.0);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0; len];
+    {
+        let mut points = points.clone();
+        let mut energy = energy.clone();
+        for rank in (0..seeded).rev() {
+            let cluster = tightest_cluster(&points, &energy);
+            points[cluster] = false;
+            update(&mut energy, cluster, -1.0);
+            ranks[cluster] = rank;
+        }
+    }
+    for rank in seeded..len {
+        let void = largest_void(&points, &energy);
+        points[void] = true;
+        update(&mut energy, void, 1.0);
+        ranks[void] = rank;
+    }
+    ranks
+}
+
+/// Maps an image onto a palette while diffusing each pixel's quantization
+/// error onto its neighbors.
+///
+/// `strength` scales the error before it's spread, so 1.0 diffuses all of it
+/// and 0.0 is the same as not dithering. With `serpentine`, odd rows are
+/// scanned right to left, which keeps the error from drifting consistently
+/// to one side of the image.
+///
+/// Fully transparent pixels are mapped as-is and neither receive nor spread
+/// any error.
+pub fn diffuse<F>(img: &Image, palette: &[u8], index_of: F, dither: Dither, strength: f32, serpentine: bool) -> Vec<u8>
+    where F: Fn(&RGBA) -> u8 {
+    let (offsets, divisor) = match dither.kernel() {
+        Some(kernel) => kernel,
+        None => return img.pixels.iter().map(&index_of).collect(),
+    };
+
+    let width = img.width as usize;
+    let height = img.height as usize;
+    let mut errors = vec![[0f32; 3]; width * height];
+    let mut indices = vec![0u8; width * height];
+
+    for y in 0..height {
+        let reversed = serpentine && y % 2 == 1;
+        for step in 0..width {
+            let x = if reversed { width - 1 - step } else { step };
+            let n = y * width + x;
+            let px = img.pixels[n];
+            if px[3] == 0 {
+                indices[n] = index_of(&px);
+                continue;
+            }
+
+            let mut wanted = [0f32; 3];
+            let mut adjusted = [0u8, 0, 0, 255];
+            for c in 0..3 {
+                wanted[c] = px[c] as f32 + errors[n][c];
+                adjusted[c] = wanted[c].clamp(0.0, 255.0).round() as u8;
+            }
+            let idx = index_of(&adjusted);
+            indices[n] = idx;
+
+            let chosen = &palette[idx as usize * 3..idx as usize * 3 + 3];
+            let mut error = [0f32; 3];
+            for c in 0..3 {
+                error[c] = (wanted[c] - chosen[c] as f32) * strength / divisor;
+            }
+
+            for &(dx, dy, share) in offsets {
+                let dx = if reversed { -dx } else { dx };
+                let nx = x as isize + dx;
+                let ny = y + dy;
+                if nx < 0 || nx >= width as isize || ny >= height {
+                    continue;
+                }
+                let neighbor = &mut errors[ny * width + nx as usize];
+                for c in 0..3 {
+                    neighbor[c] += error[c] * share;
+                }
+            }
+        }
+    }
+
+    indices
+}
+
+/// Maps an image onto a palette after offsetting each pixel by the threshold
+/// at its position in a tiled threshold map.
+///
+/// The offsets span roughly the distance between neighboring colors of a
+/// palette with `palette_len` evenly spread colors, scaled by `strength`.
+/// Fully transparent pixels are mapped as-is.
+pub fn ordered<F>(img: &Image, palette_len: usize, index_of: F, map: &ThresholdMap, strength: f32) -> Vec<u8>
+    where F: Fn(&RGBA) -> u8 {
+    let width = img.width as usize;
+    let spread = 255.0 / (palette_len.max(2) as f32).cbrt() * strength;
+    img.pixels.iter().enumerate().map(|(n, px)| {
+        if px[3] == 0 {
+            return index_of(px);
+        }
+        let offset = (map.threshold(n % width, n / width) - 0.5) * spread;
+        let mut adjusted = [0u8, 0, 0, 255];
+        for c in 0..3 {
+            adjusted[c] = (px[c] as f32 + offset).clamp(0.0, 255.0).round() as u8;
+        }
+        index_of(&adjusted)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diffuse, ordered, bayer_ranks, blue_noise_ranks, Dither, ThresholdMap};
+    use super::super::Image;
+
+    fn gray_palette() -> Vec<u8> {
+        vec![0, 0, 0, 255, 255, 255]
+    }
+
+    fn nearest_gray(px: &[u8; 4]) -> u8 {
+        if px[0] < 128 { 0 } else { 1 }
+    }
+
+    fn flat_gray(level: u8) -> Image {
+        Image {
+            pixels: vec![[level, level, level, 255]; 32 * 32],
+            width: 32,
+            height: 32,
+        }
+    }
+
+    fn white_ratio(indices: &[u8]) -> f32 {
+        indices.iter().filter(|&&i| i == 1).count() as f32 / indices.len() as f32
+    }
+
+    #[test]
+    fn test_diffusion_preserves_average() {
+        let img = flat_gray(64);
+        for &dither in &[Dither::FloydSteinberg, Dither::Sierra] {
+            let indices = diffuse(&img, &gray_palette(), nearest_gray, dither, 1.0, true);
+            let ratio = white_ratio(&indices);
+            assert!((ratio - 0.25).abs() < 0.02, "{:?} produced {} white pixels", dither, ratio);
+        }
+    }
+
+    #[test]
+    fn test_atkinson_loses_some_error() {
+        let img = flat_gray(64);
+        let indices = diffuse(&img, &gray_palette(), nearest_gray, Dither::Atkinson, 1.0, true);
+        let ratio = white_ratio(&indices);
+        assert!(ratio > 0.1 && ratio < 0.25, "Atkinson produced {} white pixels", ratio);
+    }
+
+    #[test]
+    fn test_zero_strength_is_undithered() {
+        let img = flat_gray(100);
+        let undithered = diffuse(&img, &gray_palette(), nearest_gray, Dither::None, 1.0, false);
+        let dithered = diffuse(&img, &gray_palette(), nearest_gray, Dither::FloydSteinberg, 0.0, false);
+        assert_eq!(undithered, dithered);
+        assert!(undithered.iter().all(|&i| i == 0));
+    }
+
+    #[test]
+    fn test_transparent_pixels_are_not_dithered() {
+        let mut img = flat_gray(64);
+        for px in img.pixels.iter_mut().step_by(3) {
+            *px = [255, 255, 255, 0];
+        }
+        let indices = diffuse(&img, &gray_palette(), nearest_gray, Dither::Sierra, 1.0, true);
+        for (px, idx) in img.pixels.iter().zip(indices.iter()) {
+            if px[3] == 0 {
+                assert_eq!(*idx, 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bayer_ranks() {
+        assert_eq!(bayer_ranks(2), vec![0, 2, 3, 1]);
+        assert_eq!(bayer_ranks(4), vec![
+            0, 8, 2, 10,
+            12, 4, 14, 6,
+            3, 11, 1, 9,
+            15, 7, 13, 5,
+        ]);
+    }
+
+    #[test]
+    fn test_blue_noise_ranks_are_a_permutation() {
+        let mut ranks = blue_noise_ranks(16);
+        ranks.sort();
+        assert_eq!(ranks, (0..256).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_ordered_is_stable_between_frames() {
+        let map = ThresholdMap::new(Dither::BlueNoise).unwrap();
+        let first = flat_gray(90);
+        let mut second = flat_gray(90);
+        // Change the top half of the second frame only
+        for px in second.pixels.iter_mut().take(32 * 16) {
+            *px = [200, 200, 200, 255];
+        }
+        let first_indices = ordered(&first, 2, nearest_gray, &map, 1.0);
+        let second_indices = ordered(&second, 2, nearest_gray, &map, 1.0);
+        assert_eq!(first_indices[32 * 16..], second_indices[32 * 16..]);
+        assert!(first_indices.contains(&1));
+        assert!(first_indices.contains(&0));
+    }
+
+    #[test]
+    fn test_no_threshold_map_for_other_dithers() {
+        assert!(ThresholdMap::new(Dither::None).is_none());
+        assert!(ThresholdMap::new(Dither::FloydSteinberg).is_none());
+        assert!(ThresholdMap::new(Dither::Bayer8).is_some());
+    }
+}
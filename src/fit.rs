@@ -0,0 +1,305 @@
+//! Searches for settings that make a Gif fit under a file size limit.
+//!
+//! Each try engiffens the frames again with gentler settings giving way to
+//! harsher ones, and writes the result to memory to measure it. Within each
+//! frame size and frame rate, the palette first shrinks down to 64 colors,
+//! then lossy compression grows. When that isn't enough, every other frame
+//! is dropped, and after that the frames are scaled down, starting over with
+//! the full palette each time.
+
+use std::f32;
+use super::{check_delays, engiffen_with_quantizer, Error, Gif, Image, Options, Quantize, MAX_DELAY};
+
+/// Fewest colors the palette shrinks to before lossy compression is tried.
+const MIN_COLORS: u16 = 64;
+
+/// Lossy compression levels tried once the palette is as small as it gets.
+const LOSSY_LEVELS: [u32; 3] = [20, 40, 80];
+
+/// Frames kept, as in every `n`th one, tried before scaling the frames.
+const FRAME_STEPS: [usize; 2] = [1, 2];
+
+/// Fractions of their width and height the frames are scaled to.
+const SCALES: [f32; 5] = [1.0, 0.75, 0.5, 1.0 / 3.0, 0.25];
+
+/// Settings a `Gif` from `engiffen_to_size` was made with.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Fit {
+    /// Colors in the Gif's global palette, counting the transparent one
+    pub colors: u16,
+    /// Lossy compression level the Gif is written with, 0 for lossless
+    pub lossy: u32,
+    /// Only every `frame_step`th frame was kept, with the others' delays
+    /// added to it. 1 keeps every frame.
+    pub frame_step: usize,
+    /// Fraction of the original width and height the frames were scaled to
+    pub scale: f32,
+    /// Size of the written Gif in bytes
+    pub size: usize,
+}
+
+/// Converts a sequence of images into a `Gif` like `engiffen_with_delays`,
+/// lowering the palette size, raising lossy compression, dropping frames and
+/// scaling them down as far as it takes for the written Gif to be at most
+/// `max_size` bytes. The Gif is optimized, and its `lossy` level set to
+/// what was needed. Returns the Gif along with the settings it was made with.
+///
+/// Every try quantizes the frames again, so this takes several times as long
+/// as engiffening them once.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use engiffen::{load_images, engiffen_to_size, delays_for_fps, Gif, Error, Options};
+/// # fn foo() -> Result<Gif, Error> {
+/// let images = load_images(&["tests/ball/ball01.bmp", "tests/ball/ball02.bmp"]);
+/// let delays = delays_for_fps(10.0, images.len())?;
+/// let (gif, fit) = engiffen_to_size(&images, &delays, &Options::default(), 2_000_000)?;
+/// println!("{} colors, lossy {}, {} bytes", fit.colors, fit.lossy, fit.size);
+/// # Ok(gif)
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// The same as `engiffen_with_delays`, and an Error::TooLarge containing the
+/// smallest size reached and `max_size` if no settings were small enough.
+pub fn engiffen_to_size(imgs: &[Image], delays: &[u32], options: &Options, max_size: usize) -> Result<(Gif, Fit), Error> {
+    engiffen_to_size_with_quantizer(imgs, delays, &options.quantizer, options, max_size)
+}
+
+/// Fits a `Gif` under a size limit like `engiffen_to_size`, but with a
+/// palette computed by any `Quantize` implementation like
+/// `engiffen_with_quantizer`. Quantizers that ignore the palette size they're
+/// asked for go straight from the first palette to lossy compression.
+pub fn engiffen_to_size_with_quantizer<Q: Quantize + ?Sized>(imgs: &[Image], delays: &[u32], quantizer: &Q, options: &Options, max_size: usize) -> Result<(Gif, Fit), Error> {
+    if imgs.len() != delays.len() {
+        return Err(Error::DelayCount(imgs.len(), delays.len()));
+    }
+    check_delays(delays)?;
+    let mut smallest = usize::MAX;
+    let mut last_dimensions = None;
+    for &scale in &SCALES {
+        let scaled_imgs: Vec<Image>;
+        let imgs = if scale < 1.0 {
+            // Engiffening at full size already failed if there are no images
+            let (width, height) = (scaled_length(imgs[0].width, scale), scaled_length(imgs[0].height, scale));
+            if last_dimensions == Some((width, height)) {
+                continue;
+            }
+            last_dimensions = Some((width, height));
+            scaled_imgs = imgs.iter().map(|img| scaled(img, width, height)).collect();
+            &scaled_imgs[..]
+        } else {
+            imgs
+        };
+
+        for &frame_step in &FRAME_STEPS {
+            let kept: (Vec<Image>, Vec<u32>);
+            let (imgs, delays) = if frame_step > 1 {
+                if imgs.len() < 2 {
+                    continue;
+                }
+                kept = every_nth(imgs, delays, frame_step);
+                (&kept.0[..], &kept.1[..])
+            } else {
+                (imgs, delays)
+            };
+
+            let mut colors = options.colors;
+            let mut last: Option<(u16, Vec<u8>)> = None;
+            loop {
+                let options = Options { colors, ..options.clone() };
+                let mut gif = engiffen_with_quantizer(imgs, delays, quantizer, &options)?;
+                gif.optimize = true;
+                // Quantizers with a fixed palette give the same one whatever
+                // its size, which was already written without loss
+                let unchanged = match last {
+                    Some((last_colors, ref palette)) if *palette == gif.palette => {
+                        colors = last_colors;
+                        true
+                    },
+                    _ => false,
+                };
+                let last_colors = colors <= MIN_COLORS || unchanged;
+                let lossless: &[u32] = if unchanged { &[] } else { &[0] };
+                let levels: &[u32] = if last_colors { &LOSSY_LEVELS } else { &[] };
+                for &lossy in lossless.iter().chain(levels) {
+                    gif.lossy = lossy;
+                    let mut bytes = vec![];
+                    gif.write(&mut bytes)?;
+                    #[cfg(feature = "debug-stderr")]
+                    eprintln!("{} bytes with {} colors, lossy {}, every {} frames at {}x.", bytes.len(), colors, lossy, frame_step, scale);
+                    smallest = smallest.min(bytes.len());
+                    if bytes.len() <= max_size {
+                        let fit = Fit {
+                            colors: (gif.palette.len() / 3) as u16,
+                            lossy,
+                            frame_step,
+                            scale,
+                            size: bytes.len(),
+                        };
+                        return Ok((gif, fit));
+                    }
+                }
+                if last_colors {
+                    break;
+                }
+                last = Some((colors, gif.palette));
+                colors = (colors / 2).max(MIN_COLORS);
+            }
+        }
+    }
+    Err(Error::TooLarge(smallest, max_size))
+}
+
+fn scaled_length(length: u32, scale: f32) -> u32 {
+    ((length as f32 * scale).round() as u32).max(1)
+}
+
+/// Copy of the image shrunk to the given dimensions, with each pixel the
+/// average of the pixels it covers, weighted by their alpha.
+fn scaled(img: &Image, width: u32, height: u32) -> Image {
+    let (src_width, src_height) = (img.width as usize, img.height as usize);
+    let (width, height) = (width as usize, height as usize);
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let (top, bottom) = (y * src_height / height, (y + 1) * src_height / height);
+        for x in 0..width {
+            let (left, right) = (x * src_width / width, (x + 1) * src_width / width);
+            let mut sum = [0u32; 4];
+            for src_y in top..bottom {
+                for px in &img.pixels[src_y * src_width + left..src_y * src_width + right] {
+                    let alpha = px[3] as u32;
+                    for c in 0..3 {
+                        sum[c] += px[c] as u32 * alpha;
+                    }
+                    sum[3] += alpha;
+                }
+            }
+            let count = ((bottom - top) * (right - left)) as u32;
+            pixels.push(match sum[3] {
+                0 => [0, 0, 0, 0],
+                alpha => [(sum[0] / alpha) as u8, (sum[1] / alpha) as u8, (sum[2] / alpha) as u8, (alpha / count) as u8],
+            });
+        }
+    }
+    Image {
+        pixels,
+        width: width as u32,
+        height: height as u32,
+    }
+}
+
+/// Every `step`th image, starting with the first, along with delays that
+/// hold each one for as long as the images it stands in for. An image is
+/// kept more than once if that's longer than `MAX_DELAY`.
+fn every_nth(imgs: &[Image], delays: &[u32], step: usize) -> (Vec<Image>, Vec<u32>) {
+    let mut kept = vec![];
+    let mut kept_delays = vec![];
+    for (img, chunk) in imgs.iter().step_by(step).zip(delays.chunks(step)) {
+        let mut total: u64 = chunk.iter().map(|&delay| u64::from(delay)).sum();
+        while total > u64::from(MAX_DELAY) {
+            kept.push(img.clone());
+            kept_delays.push(MAX_DELAY);
+            total -= u64::from(MAX_DELAY);
+        }
+        kept.push(img.clone());
+        kept_delays.push(total as u32);
+    }
+    (kept, kept_delays)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{engiffen_to_size, engiffen_to_size_with_quantizer, every_nth, scaled, Fit};
+    use super::super::{delays_for_fps, Error, FixedPalette, Image, Options, Quantizer, MAX_DELAY};
+
+    /// Noisy frames that take a lot of space at full quality.
+    fn noisy_frames() -> Vec<Image> {
+        let mut seed = 7u32;
+        (0..4).map(|_| {
+            let pixels = (0..48 * 48).map(|p| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                let noise = (seed >> 16) as u8 % 24;
+                [(p % 48 * 4) as u8 + noise, (p / 48 * 4) as u8 + noise, 128, 255]
+            }).collect();
+            Image { pixels, width: 48, height: 48 }
+        }).collect()
+    }
+
+    #[test]
+    fn test_scaled() {
+        let img = Image {
+            pixels: vec![[255, 0, 0, 255], [0, 0, 255, 255], [0, 0, 0, 0], [0, 255, 0, 255]],
+            width: 2,
+            height: 2,
+        };
+        let small = scaled(&img, 1, 1);
+        assert_eq!((small.width, small.height), (1, 1));
+        // The transparent pixel doesn't darken the others
+        assert_eq!(small.pixels, vec![[85, 85, 85, 191]]);
+        assert_eq!(scaled(&img, 2, 1).pixels, vec![[255, 0, 0, 127], [0, 127, 127, 255]]);
+    }
+
+    #[test]
+    fn test_every_nth() {
+        let imgs = noisy_frames();
+        let (kept, delays) = every_nth(&imgs, &[10, 20, 30, 40], 2);
+        assert_eq!(kept.len(), 2);
+        assert!(kept[0] == imgs[0] && kept[1] == imgs[2]);
+        assert_eq!(delays, vec![30, 70]);
+        let (kept, delays) = every_nth(&imgs[..3], &[10, 20, 30], 2);
+        assert_eq!((kept.len(), delays), (2, vec![30, 30]));
+
+        // Too long for one frame, so the first image is kept twice
+        let (kept, delays) = every_nth(&imgs, &[MAX_DELAY, 100, 30, 40], 2);
+        assert_eq!(delays, vec![MAX_DELAY, 100, 70]);
+        assert!(kept[0] == imgs[0] && kept[1] == imgs[0] && kept[2] == imgs[2]);
+    }
+
+    #[test]
+    fn test_engiffen_to_size() {
+        let imgs = noisy_frames();
+        let delays = delays_for_fps(10.0, imgs.len()).unwrap();
+        let options = Options { quantizer: Quantizer::Wu, ..Options::default() };
+
+        let (gif, fit) = engiffen_to_size(&imgs, &delays, &options, usize::MAX).unwrap();
+        assert_eq!(fit, Fit { colors: fit.colors, lossy: 0, frame_step: 1, scale: 1.0, size: fit.size });
+        assert_eq!((gif.width, gif.height, gif.images.len()), (48, 48, 4));
+        assert_eq!(fit.colors as usize, gif.palette.len() / 3);
+        assert!(fit.colors > 128 && fit.colors <= 256);
+        let full_size = fit.size;
+
+        let (gif, fit) = engiffen_to_size(&imgs, &delays, &options, full_size * 2 / 3).unwrap();
+        assert!(fit.size <= full_size * 2 / 3);
+        assert!(fit.colors <= 128 || fit.lossy > 0);
+        let mut bytes = vec![];
+        gif.write(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), fit.size);
+
+        let (gif, fit) = engiffen_to_size(&imgs, &delays, &options, full_size / 8).unwrap();
+        assert!(fit.size <= full_size / 8);
+        assert!(fit.frame_step > 1 || fit.scale < 1.0);
+        assert_eq!(gif.delays.iter().sum::<u32>(), 400);
+
+        match engiffen_to_size(&imgs, &delays, &options, 100) {
+            Err(Error::TooLarge(smallest, 100)) => assert!(smallest > 100),
+            other => panic!("Expected Error::TooLarge, got {:?}", other.map(|(_, fit)| fit)),
+        }
+    }
+
+    #[test]
+    fn test_fixed_palette_to_size() {
+        let imgs = noisy_frames();
+        let delays = delays_for_fps(10.0, imgs.len()).unwrap();
+        let colors = (0..64).map(|n| [n * 4, 255 - n * 4, 128]).collect();
+        let palette = FixedPalette::new(colors).unwrap();
+        let options = Options::default();
+        let (_, full) = engiffen_to_size_with_quantizer(&imgs, &delays, &palette, &options, usize::MAX).unwrap();
+        let (_, fit) = engiffen_to_size_with_quantizer(&imgs, &delays, &palette, &options, full.size - 1).unwrap();
+        // Asking for fewer colors doesn't change a fixed palette
+        assert_eq!((full.colors, fit.colors), (64, 64));
+        assert!(fit.lossy > 0 || fit.frame_step > 1);
+    }
+}
@@ -0,0 +1,211 @@
+//! Plans the frames written for a Gif, keeping track of what each one leaves
+//! on the canvas for the next.
+
+use std::borrow::Cow;
+use gif::{DisposalMethod, Frame};
+use super::{Disposal, Gif, Rgb};
+
+/// A pixel's color, or `None` where the canvas is see-through.
+type Color = Option<Rgb>;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+struct Rect {
+    left: usize,
+    top: usize,
+    right: usize,
+    bottom: usize,
+}
+
+impl Rect {
+    fn union(&self, other: &Rect) -> Rect {
+        Rect {
+            left: self.left.min(other.left),
+            top: self.top.min(other.top),
+            right: self.right.max(other.right),
+            bottom: self.bottom.max(other.bottom),
+        }
+    }
+
+    fn pixels(&self, width: usize) -> Vec<usize> {
+        let mut pixels = Vec::with_capacity((self.right - self.left) * (self.bottom - self.top));
+        for y in self.top..self.bottom {
+            for x in self.left..self.right {
+                pixels.push(y * width + x);
+            }
+        }
+        pixels
+    }
+}
+
+/// Smallest rectangle containing every pixel for which `f` is true.
+fn bounds<F>(width: usize, height: usize, f: F) -> Option<Rect>
+    where F: Fn(usize) -> bool {
+    let mut rect: Option<Rect> = None;
+    for y in 0..height {
+        for x in 0..width {
+            if f(y * width + x) {
+                let point = Rect { left: x, top: y, right: x + 1, bottom: y + 1 };
+                rect = Some(rect.map_or(point, |r| r.union(&point)));
+            }
+        }
+    }
+    rect
+}
+
+/// Colors of the `n`th frame's pixels, looked up in the palette it uses.
+fn colors(gif: &Gif, n: usize) -> Vec<Color> {
+    let (palette, transparency) = gif.frame_palette(n);
+    gif.images[n].iter().map(|&idx| {
+        if Some(idx) == transparency {
+            None
+        } else {
+            let i = idx as usize * 3;
+            match palette.get(i..i + 3) {
+                Some(rgb) => Some([rgb[0], rgb[1], rgb[2]]),
+                None => Some([0, 0, 0]),
+            }
+        }
+    }).collect()
+}
+
+/// Number of entries in the color table written for the palette, which is
+/// padded to a power of two.
+fn color_table_len(palette: &[u8]) -> usize {
+    (palette.len() / 3).max(2).next_power_of_two()
+}
+
+/// Iterator over the frames to write for a Gif, without their delays.
+///
+/// Each frame's disposal method comes from the Gif's `disposals`. Frames
+/// with `Disposal::Auto` are kept on the canvas when they're done, unless the
+/// next frame needs some of their pixels to become transparent again, since
+/// transparent pixels can't be drawn over opaque ones. Those frames are
+/// restored to the background instead, so sprites moving over a transparent
+/// background don't leave trails.
+///
+/// When the Gif is optimized, each frame only covers the area that differs
+/// from the canvas left by the frames before it, grown to cover any pixels it
+/// has to clear for the next frame. Pixels inside that area that already
+/// show the right color are set to a transparent index so they compress
+/// better. That's the transparent index of the frame's palette if it has
+/// one, or else an index that isn't used inside the area.
+///
+/// Frames with a local palette carry it as their color table.
+pub struct Frames<'a> {
+    gif: &'a Gif,
+    position: usize,
+    canvas: Vec<Color>,
+    next: Option<Vec<Color>>,
+}
+
+impl<'a> Frames<'a> {
+    pub fn new(gif: &'a Gif) -> Frames<'a> {
+        Frames {
+            gif,
+            position: 0,
+            canvas: vec![None; gif.width as usize * gif.height as usize],
+            next: if gif.images.is_empty() { None } else { Some(colors(gif, 0)) },
+        }
+    }
+}
+
+impl<'a> Iterator for Frames<'a> {
+    type Item = Frame<'a>;
+
+    fn next(&mut self) -> Option<Frame<'a>> {
+        let gif = self.gif;
+        let img = gif.images.get(self.position)?;
+        let width = gif.width as usize;
+        let height = gif.height as usize;
+        let target = self.next.take().expect("Colors of the current frame were not computed.");
+        self.next = if self.position + 1 < gif.images.len() { Some(colors(gif, self.position + 1)) } else { None };
+        let (palette, transparency) = gif.frame_palette(self.position);
+        let local_palette = gif.local_palettes.get(self.position).and_then(|local| local.as_ref());
+
+        let full = Rect { left: 0, top: 0, right: width, bottom: height };
+        let mut rect = if gif.optimize {
+            let canvas = &self.canvas;
+            bounds(width, height, |p| target[p] != canvas[p])
+                .unwrap_or(Rect { left: 0, top: 0, right: 1, bottom: 1 })
+        } else {
+            full
+        };
+
+        let needs_clearing = match self.next {
+            Some(ref next) => {
+                let canvas = &self.canvas;
+                let drawn = |p: usize| target[p].is_some() || canvas[p].is_some();
+                bounds(width, height, |p| next[p].is_none() && drawn(p))
+            },
+            None => None,
+        };
+        let dispose = match gif.disposals.get(self.position).cloned().unwrap_or(Disposal::Auto) {
+            Disposal::Auto if needs_clearing.is_some() => DisposalMethod::Background,
+            Disposal::Auto | Disposal::Keep => DisposalMethod::Keep,
+            Disposal::Background => DisposalMethod::Background,
+            Disposal::Previous => DisposalMethod::Previous,
+        };
+        if dispose == DisposalMethod::Background {
+            if let Some(clear) = needs_clearing {
+                rect = rect.union(&clear);
+            }
+        }
+
+        let pixels = rect.pixels(width);
+        let (buffer, transparent) = if gif.optimize {
+            let transparent = transparency.or_else(|| {
+                let mut used = vec![false; 256];
+                for &p in &pixels {
+                    used[img[p] as usize] = true;
+                }
+                (0..color_table_len(palette)).find(|&idx| !used[idx]).map(|idx| idx as u8)
+            });
+            let canvas = &self.canvas;
+            let buffer: Vec<u8> = pixels.iter().map(|&p| {
+                match transparent {
+                    Some(t) if target[p] == canvas[p] => t,
+                    _ => img[p],
+                }
+            }).collect();
+            (Cow::Owned(buffer), transparent)
+        } else {
+            (Cow::Borrowed(&img[..]), transparency)
+        };
+
+        let previous = if dispose == DisposalMethod::Previous {
+            Some(self.canvas.clone())
+        } else {
+            None
+        };
+        for (&p, &idx) in pixels.iter().zip(buffer.iter()) {
+            if Some(idx) != transparent {
+                self.canvas[p] = target[p];
+            }
+        }
+        match dispose {
+            DisposalMethod::Background => {
+                for &p in &pixels {
+                    self.canvas[p] = None;
+                }
+            },
+            DisposalMethod::Previous => {
+                self.canvas = previous.expect("Canvas to restore was not saved.");
+            },
+            _ => {},
+        }
+
+        self.position += 1;
+
+        Some(Frame {
+            left: rect.left as u16,
+            top: rect.top as u16,
+            width: (rect.right - rect.left) as u16,
+            height: (rect.bottom - rect.top) as u16,
+            buffer,
+            transparent,
+            dispose,
+            palette: local_palette.map(|local| local.colors.clone()),
+            ..Frame::default()
+        })
+    }
+}
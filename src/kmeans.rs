@@ -0,0 +1,105 @@
+//! K-means refinement of a palette in Lab space.
+//!
+//! Each palette color is the center of a cluster. Every sampled pixel joins
+//! the cluster of its nearest center, then each center moves to the mean of
+//! its cluster, over and over until the centers stop moving or the iteration
+//! cap is reached.
+
+use lab::Lab;
+use rayon::prelude::*;
+use super::Rgb;
+
+/// How little the centers have to move, as a squared distance in Lab space,
+/// for the clusters to count as settled.
+const SETTLED: f32 = 0.01;
+
+fn closest(centers: &[Lab], color: &Lab) -> usize {
+    centers.iter().enumerate().fold((0, f32::INFINITY), |closest, (idx, center)| {
+        let dist = center.squared_distance(color);
+        if dist < closest.1 { (idx, dist) } else { closest }
+    }).0
+}
+
+/// Moves the `palette` colors to better fit the `samples`, for at most
+/// `iterations` rounds. Colors that no sample is closest to stay put.
+pub fn refine(palette: &[Rgb], samples: &[Lab], iterations: u32) -> Vec<Rgb> {
+    let mut centers: Vec<Lab> = palette.iter().map(Lab::from_rgb).collect();
+    let mut moved_ever = vec![false; centers.len()];
+
+    for _ in 0..iterations {
+        let sums = samples.par_chunks(4096).map(|chunk| {
+            let mut sums = vec![(0f64, 0f64, 0f64, 0usize); centers.len()];
+            for sample in chunk {
+                let sum = &mut sums[closest(&centers, sample)];
+                sum.0 += sample.l as f64;
+                sum.1 += sample.a as f64;
+                sum.2 += sample.b as f64;
+                sum.3 += 1;
+            }
+            sums
+        }).reduce(|| vec![(0f64, 0f64, 0f64, 0usize); centers.len()], |mut sums, other| {
+            for (sum, other) in sums.iter_mut().zip(other.iter()) {
+                sum.0 += other.0;
+                sum.1 += other.1;
+                sum.2 += other.2;
+                sum.3 += other.3;
+            }
+            sums
+        });
+
+        let mut moved = 0f32;
+        for ((center, sum), moved_ever) in centers.iter_mut().zip(sums.iter()).zip(moved_ever.iter_mut()) {
+            if sum.3 == 0 {
+                continue;
+            }
+            let count = sum.3 as f64;
+            let mean = Lab { l: (sum.0 / count) as f32, a: (sum.1 / count) as f32, b: (sum.2 / count) as f32 };
+            let distance = mean.squared_distance(center);
+            if distance > 0.0 {
+                *moved_ever = true;
+            }
+            moved = moved.max(distance);
+            *center = mean;
+        }
+        if moved < SETTLED {
+            break;
+        }
+    }
+
+    // Converting back from Lab can be off by one, so keep the exact color
+    // where a center never moved
+    centers.iter().zip(palette.iter()).zip(moved_ever.iter()).map(|((center, color), &moved)| {
+        if moved { center.to_rgb() } else { *color }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use lab::Lab;
+    use super::refine;
+
+    #[test]
+    fn test_moves_to_cluster_means() {
+        let samples: Vec<Lab> = [[250, 0, 0], [230, 0, 0], [0, 0, 200], [0, 0, 220]].iter()
+            .map(Lab::from_rgb)
+            .collect();
+        let refined = refine(&[[128, 0, 0], [0, 0, 128]], &samples, 10);
+        let expected_red = Lab { l: (samples[0].l + samples[1].l) / 2.0, a: (samples[0].a + samples[1].a) / 2.0, b: (samples[0].b + samples[1].b) / 2.0 };
+        assert!(Lab::from_rgb(&refined[0]).squared_distance(&expected_red) < 2.0);
+        assert!(refined[1][2] > 190 && refined[1][0] < 10);
+    }
+
+    #[test]
+    fn test_unused_colors_stay() {
+        let samples = vec![Lab::from_rgb(&[10, 200, 10])];
+        let refined = refine(&[[0, 255, 0], [255, 255, 255]], &samples, 5);
+        assert_eq!(refined[1], [255, 255, 255]);
+        assert!(Lab::from_rgb(&refined[0]).squared_distance(&samples[0]) < 2.0);
+    }
+
+    #[test]
+    fn test_zero_iterations() {
+        let samples = vec![Lab::from_rgb(&[10, 200, 10])];
+        assert_eq!(refine(&[[0, 255, 0]], &samples, 0), vec![[0, 255, 0]]);
+    }
+}
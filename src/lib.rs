@@ -11,18 +11,41 @@ extern crate color_quant;
 extern crate lab;
 extern crate rayon;
 extern crate fnv;
+extern crate rand;
 
+use std::cmp::Reverse;
 use std::io;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
 use std::{error, fmt, f32};
-use std::borrow::Cow;
 use std::path::Path;
 use image::GenericImage;
-use gif::{Frame, Encoder, Repeat, SetParameter};
+use gif::{Repeat, SetParameter};
 use color_quant::NeuQuant;
 use lab::Lab;
 use rayon::prelude::*;
 use fnv::FnvHashMap;
 
+mod dither;
+mod fit;
+mod frames;
+mod kmeans;
+mod lzw;
+mod median_cut;
+mod octree;
+mod palette_file;
+mod reservoir;
+mod scenes;
+mod source;
+mod writer;
+mod wu;
+pub use dither::Dither;
+pub use fit::{engiffen_to_size, engiffen_to_size_with_quantizer, Fit};
+pub use palette_file::{FixedPalette, PaletteFormat};
+pub use scenes::detect_scenes;
+use reservoir::Reservoir;
+use source::{Packed, Source};
+
 #[cfg(feature = "debug-stderr")] use std::time::{Instant};
 
 #[cfg(feature = "debug-stderr")]
@@ -32,17 +55,34 @@ fn ms(duration: Instant) -> u64 {
 }
 
 type RGBA = [u8; 4];
+type Rgb = [u8; 3];
 
 /// A color quantizing strategy.
 ///
-/// `Naive` calculates color frequencies, picks the 256 most frequent colors
-/// to be the palette, then reassigns the less frequently occuring colors to
+/// `Naive` calculates color frequencies, picks the most frequent colors to be
+/// the palette, then reassigns the less frequently occuring colors to
 /// the closest matching palette color.
 ///
 /// `NeuQuant` uses the NeuQuant algorithm from the `color_quant` crate. It
 /// trains a neural network using a pseudorandom subset of pixels, then
 /// assigns each pixel its closest matching color in the palette.
 ///
+/// `MedianCut` puts every color in a box, then keeps splitting the box with
+/// the widest range of red, green or blue in half at its median pixel, until
+/// there is one box per palette color. Each pixel gets the average color of
+/// its box.
+///
+/// `Octree` sorts colors into a tree that splits the color cube into eight
+/// smaller cubes at every level, and merges the least used branches whenever
+/// it has too many leaves. Each leaf becomes a palette color. Since the tree
+/// never grows past a fixed size, it uses little memory however many colors
+/// the images have.
+///
+/// `Wu` is Xiaolin Wu's quantizer. It counts colors in a coarse grid, then
+/// keeps cutting the box of colors with the most variance in two, choosing
+/// the cut that leaves the least variance behind, until there is one box per
+/// palette color.
+///
 /// # Usage
 ///
 /// Pass this as the last argument to `engiffen` to select the quantizing
@@ -53,19 +93,285 @@ type RGBA = [u8; 4];
 /// which can greatly reduce its workload. Specifically, for a value of N,
 /// only the pixels on every Nth column of every Nth row are considered, so
 /// a value of 1 trains using every pixel, while a value of 2 trains using
-/// 1/4 of all pixels.
+/// 1/4 of all pixels. Of those, it trains on at most about a million, picked
+/// at random from every frame, so long sequences don't need a copy of every
+/// pixel.
 ///
 /// The `Naive` strategy is fastest when you know that your input images
 /// have a limited color range, but will produce terrible banding otherwise.
+///
+/// The `MedianCut` strategy is deterministic and fast, and does well on flat
+/// colored art, but is not as good as `NeuQuant` on smooth gradients.
+///
+/// The `Octree` strategy is also deterministic, and suits long photographic
+/// sequences with millions of distinct colors, where `Naive` and `MedianCut`
+/// would have to count every one of them.
+///
+/// The `Wu` strategy looks nearly as good as `NeuQuant`, in much less time
+/// on long sequences, since it only has to count every pixel once.
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub enum Quantizer {
     Naive,
     NeuQuant(u32),
+    MedianCut,
+    Octree,
+    Wu,
+}
+
+/// Maps a color to the index of the palette color that stands in for it.
+pub type IndexFn = Box<dyn Fn(&[u8; 4]) -> u8 + Sync>;
+
+/// A palette computed for a sequence of images.
+///
+/// `colors` holds the red, green and blue bytes of up to 256 colors.
+/// `transparency` is the index of the color that stands in for transparent
+/// pixels, if there is one. `index_of` maps every color to its palette
+/// index, including colors that aren't in the images, since dithering makes
+/// up new ones.
+pub struct Palette {
+    pub colors: Vec<u8>,
+    pub transparency: Option<u8>,
+    pub index_of: IndexFn,
+}
+
+impl fmt::Debug for Palette {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Palette {{ colors: Vec<u8 x {}>, transparency: {:?} }}", self.colors.len(), self.transparency)
+    }
+}
+
+/// A color quantizing algorithm, which computes a palette for a sequence of
+/// images.
+///
+/// Every `Quantizer` implements it. Other algorithms can be used by
+/// implementing it and passing them to `engiffen_with_quantizer`.
+///
+/// # Examples
+///
+/// ```rust
+/// # use engiffen::{Image, Palette, Quantize};
+/// // Maps every pixel to black or white
+/// struct Threshold;
+///
+/// impl Quantize for Threshold {
+///     fn palette(&self, _imgs: &[Image], _max_colors: usize) -> Palette {
+///         Palette {
+///             colors: vec![0, 0, 0, 255, 255, 255],
+///             transparency: None,
+///             index_of: Box::new(|px| {
+///                 let luma = px[0] as u32 * 3 + px[1] as u32 * 6 + px[2] as u32;
+///                 if luma < 1280 { 0 } else { 1 }
+///             }),
+///         }
+///     }
+/// }
+/// ```
+pub trait Quantize {
+    /// Computes the palette for the images, which all have the same
+    /// dimensions. It should have no more than `max_colors` colors, counting
+    /// the transparent one, which is between 2 and 256, and its `index_of`
+    /// must only return the indices of those colors.
+    fn palette(&self, imgs: &[Image], max_colors: usize) -> Palette;
+
+    /// Whether the palette's colors have to stay exactly as they are, so
+    /// `kmeans` doesn't refine them. False unless overridden.
+    fn is_fixed(&self) -> bool {
+        false
+    }
+}
+
+impl Quantize for Quantizer {
+    fn palette(&self, imgs: &[Image], max_colors: usize) -> Palette {
+        self.stats(imgs, 0..imgs.len()).palette(max_colors)
+    }
+}
+
+impl Quantizer {
+    /// Color statistics gathered from the frames in `range`.
+    fn stats<S: Source + ?Sized>(self, frames: &S, range: Range<usize>) -> PaletteStats {
+        #[cfg(feature = "debug-stderr")] let time_stats = Instant::now();
+        let stats = range.into_par_iter().map(|n| {
+            let mut stats = PaletteStats::new(self);
+            stats.add(&frames.frame(n));
+            stats
+        }).reduce(|| PaletteStats::new(self), PaletteStats::merge);
+        #[cfg(feature = "debug-stderr")]
+        eprintln!("Gathered color statistics in {} ms.", ms(time_stats));
+
+        stats
+    }
+}
+
+/// Settings for converting a sequence of images into a `Gif`.
+///
+/// `quantizer` selects the algorithm that computes the palette.
+///
+/// `dither` selects how pixels are mapped onto that palette. With an error
+/// diffusion kernel, `dither_strength` scales how much of each pixel's error
+/// is spread to its neighbors (from 0.0 for none to 1.0 for all of it), and
+/// `serpentine` scans every other row right to left. With an ordered dither,
+/// `dither_strength` scales how far pixels are offset by the threshold map.
+///
+/// `kmeans` refines the palette from any quantizer with up to that many
+/// rounds of k-means clustering in Lab space, over a sample of pixels from
+/// every frame. Each palette color moves to the perceptual average of the
+/// pixels closest to it, which improves how the `Gif` looks at some cost in
+/// time. It's off at 0, the default. Fixed palettes, like a `FixedPalette`,
+/// are never refined.
+///
+/// `colors` is the most colors the palette can have, from 2 to 256 (the
+/// default), counting the transparent color if the images have one. Smaller
+/// palettes make smaller files, since the color table shrinks and pixels
+/// take fewer bits to encode. This suits screen captures of user interfaces,
+/// which have few colors to begin with.
+///
+/// `palette_mode` picks whether frames share one palette or may get palettes
+/// of their own. See `PaletteMode`.
+///
+/// Gifs can't show partly transparent pixels, so pixels with less alpha than
+/// `alpha_threshold` become fully transparent and the rest are drawn fully
+/// opaque. The default of 1 only makes pixels with no alpha at all
+/// transparent, while 0 draws every pixel opaque. Transparent pixels get a
+/// palette entry of their own, whichever quantizer computes the palette.
+///
+/// With a `matte`, the partly transparent pixels that are drawn are blended
+/// over it first, instead of just losing their transparency. Soft edges then
+/// look right on a page whose background matches the matte.
+///
+/// With `dedupe`, frames that come out exactly the same as the frame before
+/// them, pixel for pixel and palette for palette, are dropped, and their
+/// delays added to the frame that's kept. This suits screen recordings, which
+/// hold still for long runs of frames. A run longer than `MAX_DELAY` keeps
+/// as many frames as it needs to hold its whole duration.
+///
+/// # Examples
+///
+/// ```rust
+/// # use engiffen::{Options, Dither, Quantizer};
+/// let options = Options {
+///     quantizer: Quantizer::NeuQuant(2),
+///     dither: Dither::FloydSteinberg,
+///     dither_strength: 0.75,
+///     ..Options::default()
+/// };
+/// ```
+#[derive(Debug, PartialEq, Clone)]
+pub struct Options {
+    pub quantizer: Quantizer,
+    pub dither: Dither,
+    pub dither_strength: f32,
+    pub serpentine: bool,
+    pub kmeans: u32,
+    pub colors: u16,
+    pub palette_mode: PaletteMode,
+    pub alpha_threshold: u8,
+    pub matte: Option<Matte>,
+    pub dedupe: bool,
+}
+
+impl Options {
+    /// Palette size the options ask for, within what a Gif can hold.
+    fn max_colors(&self) -> usize {
+        self.colors.clamp(2, 256) as usize
+    }
+
+    /// Whether images have to be flattened before they're quantized.
+    fn flattens(&self) -> bool {
+        self.alpha_threshold != 1 || self.matte.is_some()
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            quantizer: Quantizer::NeuQuant(1),
+            dither: Dither::None,
+            dither_strength: 1.0,
+            serpentine: true,
+            kmeans: 0,
+            colors: 256,
+            palette_mode: PaletteMode::Global,
+            alpha_threshold: 1,
+            matte: None,
+            dedupe: false,
+        }
+    }
+}
+
+impl From<Quantizer> for Options {
+    fn from(quantizer: Quantizer) -> Options {
+        Options {
+            quantizer,
+            ..Options::default()
+        }
+    }
+}
+
+/// Whether the frames of a `Gif` share a palette.
+///
+/// `Global` computes one palette over every frame, which all frames use.
+///
+/// `PerFrame` also computes a palette for each frame on its own, from the
+/// same quantizer and options, and writes it as that frame's local color
+/// table. Clips that cut between scenes with very different colors keep
+/// their colors, at a cost of up to 768 bytes per frame.
+///
+/// `PerScene` splits the frames into scenes with `detect_scenes`, and
+/// computes a palette for each scene that all its frames use. A clip with a
+/// single scene just uses the global palette.
+///
+/// `Auto` computes both a global palette and a palette for each frame, and
+/// only gives a frame its own palette when that makes it look materially
+/// closer to the source than the global palette does.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub enum PaletteMode {
+    Global,
+    PerFrame,
+    PerScene,
+    Auto,
+}
+
+/// A background that partly transparent pixels are blended over.
+///
+/// `Color` blends every pixel over the same color. `Image` blends each pixel
+/// over the pixel at the same position in the image, which is tiled if it's
+/// smaller than the frames, like a checkerboard or the page the `Gif` will
+/// be shown on.
+///
+/// # Examples
+///
+/// ```rust
+/// # use engiffen::{Matte, Options};
+/// let options = Options {
+///     matte: Some(Matte::Color([255, 255, 255])),
+///     ..Options::default()
+/// };
+/// ```
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Matte {
+    Color([u8; 3]),
+    Image(Arc<Image>),
+}
+
+impl Matte {
+    fn color_at(&self, x: usize, y: usize) -> Rgb {
+        match *self {
+            Matte::Color(color) => color,
+            Matte::Image(ref img) => {
+                let (width, height) = (img.width.max(1) as usize, img.height.max(1) as usize);
+                match img.pixels.get((y % height) * width + x % width) {
+                    Some(px) => [px[0], px[1], px[2]],
+                    None => [0, 0, 0],
+                }
+            },
+        }
+    }
 }
 
 /// An image, currently a wrapper around `image::DynamicImage`. If loaded from
 /// disk through the `load_image` or `load_images` functions, its path property
 /// contains the path used to read it from disk.
+#[derive(Eq, PartialEq, Clone)]
 pub struct Image {
     pub pixels: Vec<RGBA>,
     pub width: u32,
@@ -82,8 +388,16 @@ impl fmt::Debug for Image {
 pub enum Error {
     NoImages,
     Mismatch((u32, u32), (u32, u32)),
+    DelayCount(usize, usize),
     ImageLoad(image::ImageError),
     ImageWrite(io::Error),
+    PaletteLoad(io::Error),
+    PaletteFormat(String),
+    PaletteWrite(io::Error),
+    TooLarge(usize, usize),
+    DelayRange(u32),
+    FrameRate(f64),
+    InvalidPalette(String),
 }
 
 impl From<image::ImageError> for Error {
@@ -103,8 +417,16 @@ impl fmt::Display for Error {
         match *self {
             Error::NoImages => write!(f, "No frames sent for engiffening"),
             Error::Mismatch(_, _) => write!(f, "Frames don't have the same dimensions"),
+            Error::DelayCount(frames, delays) => write!(f, "Got {} delays for {} frames", delays, frames),
             Error::ImageLoad(ref e) => write!(f, "Image load error: {}", e),
             Error::ImageWrite(ref e) => write!(f, "Image write error: {}", e),
+            Error::PaletteLoad(ref e) => write!(f, "Palette load error: {}", e),
+            Error::PaletteFormat(ref message) => write!(f, "Palette format error: {}", message),
+            Error::PaletteWrite(ref e) => write!(f, "Palette write error: {}", e),
+            Error::TooLarge(smallest, max_size) => write!(f, "Couldn't fit the gif in {} bytes, the smallest was {} bytes", max_size, smallest),
+            Error::DelayRange(delay) => write!(f, "A frame delay of {} ms is longer than a Gif can hold", delay),
+            Error::FrameRate(fps) => write!(f, "Frame rate {} isn't a positive number", fps),
+            Error::InvalidPalette(ref message) => write!(f, "Quantizer returned an invalid palette: {}", message),
         }
     }
 }
@@ -114,13 +436,85 @@ impl error::Error for Error {
         match *self {
             Error::NoImages => "No frames sent for engiffening",
             Error::Mismatch(_, _) => "Frames don't have the same dimensions",
+            Error::DelayCount(_, _) => "Number of delays doesn't match the number of frames",
             Error::ImageLoad(_) => "Unable to load image",
             Error::ImageWrite(_) => "Unable to write image",
+            Error::PaletteLoad(_) => "Unable to load palette",
+            Error::PaletteFormat(_) => "Invalid palette",
+            Error::PaletteWrite(_) => "Unable to write palette",
+            Error::TooLarge(_, _) => "Unable to fit the gif under the size limit",
+            Error::DelayRange(_) => "Frame delay is too long",
+            Error::FrameRate(_) => "Invalid frame rate",
+            Error::InvalidPalette(_) => "Quantizer returned an invalid palette",
         }
     }
 }
 
+/// How many times a `Gif` plays.
+///
+/// `Infinite` loops forever.
+///
+/// `Repeat(n)` plays the animation once and then repeats it `n` more times,
+/// stopping on the last frame. This is the count stored in the Gif's NETSCAPE
+/// application extension. `Repeat(0)` is the same as `Once`.
+///
+/// `Once` plays the animation a single time by leaving out the NETSCAPE
+/// extension entirely.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub enum Loop {
+    Infinite,
+    Repeat(u16),
+    Once,
+}
+
+/// What happens to a frame of a `Gif` once its delay is over, before the next
+/// frame is drawn over it.
+///
+/// `Keep` leaves the frame in place, so the next frame's transparent pixels
+/// show it through. `Background` clears the frame's area back to the
+/// background, which viewers show as transparent. `Previous` restores the
+/// area to how it looked before the frame was drawn.
+///
+/// `Auto` keeps the frame, unless the next frame is transparent anywhere this
+/// frame left opaque pixels. Since transparent pixels can't erase what's under
+/// them, that frame is cleared to the background instead. This stops sprites
+/// on transparent backgrounds from leaving trails.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub enum Disposal {
+    Auto,
+    Keep,
+    Background,
+    Previous,
+}
+
 /// Struct representing an animated Gif
+///
+/// `delays` holds how long each of the `images` is displayed, in milliseconds.
+/// Gifs store delays in hundredths of a second, so they're rounded to the
+/// nearest 10 ms when written.
+///
+/// `looping` sets how many times the animation plays, and is `Loop::Infinite`
+/// for Gifs made by `engiffen`.
+///
+/// `disposals` holds what happens to each of the `images` once it's been
+/// displayed. Gifs made by `engiffen` use `Disposal::Auto` for every frame.
+///
+/// With `optimize`, each frame after the first is written as only the
+/// rectangle that changed since the previous one, with unchanged pixels
+/// inside it made transparent. This can shrink animations with still
+/// backgrounds considerably. `images` always holds the full frames.
+///
+/// `local_palettes` holds a palette for each of the `images` that doesn't use
+/// the global `palette`, which is written as the frame's local color table.
+/// Gifs made by `engiffen` only have local palettes if the options' palette
+/// mode asks for them.
+///
+/// With `lossy` above 0, frames are compressed by a lossy LZW encoder, which
+/// lets pixels take a palette color up to that distance from their own in
+/// RGB space when that makes the image data compress better. Around 10 to
+/// 40 shrinks noisy or dithered frames a lot while staying hard to notice.
+/// The pixels in `images` are left as they are. It's 0, off, for Gifs made
+/// by `engiffen`.
 #[derive(Eq, PartialEq, Clone, Hash)]
 pub struct Gif {
     pub palette: Vec<u8>,
@@ -128,18 +522,43 @@ pub struct Gif {
     pub width: u16,
     pub height: u16,
     pub images: Vec<Vec<u8>>,
-    pub delay: u16,
+    pub delays: Vec<u32>,
+    pub looping: Loop,
+    pub disposals: Vec<Disposal>,
+    pub local_palettes: Vec<Option<LocalPalette>>,
+    pub optimize: bool,
+    pub lossy: u32,
+}
+
+/// The palette of a single frame of a `Gif`, in place of the global one.
+/// `colors` holds the red, green and blue bytes of up to 256 colors, and
+/// `transparency` the index of the transparent color, if there is one.
+#[derive(Eq, PartialEq, Clone, Hash)]
+pub struct LocalPalette {
+    pub colors: Vec<u8>,
+    pub transparency: Option<u8>,
+}
+
+impl fmt::Debug for LocalPalette {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LocalPalette {{ colors: Vec<u8 x {:?}>, transparency: {:?} }}", self.colors.len(), self.transparency)
+    }
 }
 
 impl fmt::Debug for Gif {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Gif {{ palette: Vec<u8 x {:?}>, transparency: {:?}, width: {:?}, height: {:?}, images: Vec<Vec<u8> x {:?}>, delay: {:?} }}",
+        write!(f, "Gif {{ palette: Vec<u8 x {:?}>, transparency: {:?}, width: {:?}, height: {:?}, images: Vec<Vec<u8> x {:?}>, delays: Vec<u32 x {:?}>, looping: {:?}, disposals: Vec<Disposal x {:?}>, local_palettes: Vec<Option<LocalPalette> x {:?}>, optimize: {:?}, lossy: {:?} }}",
             self.palette.len(),
             self.transparency,
             self.width,
             self.height,
             self.images.len(),
-            self.delay
+            self.delays.len(),
+            self.looping,
+            self.disposals.len(),
+            self.local_palettes.len(),
+            self.optimize,
+            self.lossy
         )
     }
 }
@@ -163,21 +582,78 @@ impl Gif {
     ///
     /// # Errors
     ///
-    /// Returns the `std::io::Result` of the underlying `write` function calls.
+    /// Returns the `std::io::Result` of the underlying `write` function calls,
+    /// an Error::DelayCount if there isn't one delay for each of the images,
+    /// or an Error::DelayRange if a delay is longer than `MAX_DELAY`.
     pub fn write<W: io::Write>(&self, mut out: &mut W) -> Result<(), Error> {
-        let mut encoder = Encoder::new(&mut out, self.width, self.height, &self.palette)?;
-        encoder.set(Repeat::Infinite)?;
-        for img in &self.images {
-            let mut frame = Frame::default();
-            frame.delay = self.delay / 10;
-            frame.width = self.width;
-            frame.height = self.height;
-            frame.buffer = Cow::Borrowed(&*img);
-            frame.transparent = self.transparency;
+        if self.lossy > 0 {
+            return writer::write(self, out, self.lossy);
+        }
+        self.check_delays()?;
+        let mut encoder = gif::Encoder::new(&mut out, self.width, self.height, &self.palette)?;
+        match self.looping {
+            Loop::Infinite => encoder.set(Repeat::Infinite)?,
+            Loop::Repeat(count) => encoder.set(Repeat::Finite(count))?,
+            Loop::Once => {},
+        }
+        for (mut frame, &delay) in frames::Frames::new(self).zip(self.delays.iter()) {
+            frame.delay = centiseconds(delay);
             encoder.write_frame(&frame)?;
         }
         Ok(())
     }
+
+    /// Saves the Gif's palette to a file, so later Gifs can be mapped onto
+    /// the same colors with `FixedPalette::load`. The format is picked from
+    /// the file extension: `.gpl`, `.act`, `.hex` or `.txt`, or `.png` for a
+    /// swatch image. Only the global palette is saved, not the frames' local
+    /// palettes.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use engiffen::{Image, engiffen, Quantizer};
+    /// # fn foo() -> Result<(), engiffen::Error> {
+    /// # let images: Vec<Image> = vec![];
+    /// let gif = engiffen(&images, 10, Quantizer::Wu)?;
+    /// gif.save_palette("brand.gpl")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns Error::PaletteFormat if the file has any other extension, and
+    /// Error::PaletteWrite if the file can't be written.
+    pub fn save_palette<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        FixedPalette::from(self).save(path)
+    }
+
+    /// Writes the Gif's palette to any output that implements Write, in the
+    /// given format.
+    ///
+    /// # Errors
+    ///
+    /// Returns Error::PaletteWrite if writing fails.
+    pub fn write_palette<W: io::Write>(&self, out: &mut W, format: PaletteFormat) -> Result<(), Error> {
+        FixedPalette::from(self).write(out, format)
+    }
+
+    /// Checks that every image has a delay a Gif can hold.
+    fn check_delays(&self) -> Result<(), Error> {
+        if self.images.len() != self.delays.len() {
+            return Err(Error::DelayCount(self.images.len(), self.delays.len()));
+        }
+        check_delays(&self.delays)
+    }
+
+    /// Colors and transparent index of the palette the `n`th frame uses.
+    fn frame_palette(&self, n: usize) -> (&[u8], Option<u8>) {
+        match self.local_palettes.get(n) {
+            Some(Some(local)) => (&local.colors, local.transparency),
+            _ => (&self.palette, self.transparency),
+        }
+    }
 }
 
 /// Loads an image from the given file path.
@@ -204,7 +680,7 @@ pub fn load_image<P>(path: P) -> Result<Image, Error>
         pixels.push(px.data);
     }
     Ok(Image {
-        pixels: pixels,
+        pixels,
         width: img.width(),
         height: img.height(),
     })
@@ -232,7 +708,7 @@ pub fn load_images<P>(paths: &[P]) -> Vec<Image>
 }
 
 /// Converts a sequence of images into a `Gif` at a given frame rate. The `quantizer`
-/// parameter selects the algorithm that quantizes the palette into 256-colors.
+/// parameter selects the algorithm that quantizes the palette into 256 colors.
 ///
 /// # Examples
 ///
@@ -250,15 +726,171 @@ pub fn load_images<P>(paths: &[P]) -> Vec<Image>
 /// # Errors
 ///
 /// If any image dimensions differ, this function will return an Error::Mismatch
-/// containing tuples of the conflicting image dimensions.
+/// containing tuples of the conflicting image dimensions. An `fps` of 0 returns
+/// an Error::FrameRate.
 pub fn engiffen(imgs: &[Image], fps: usize, quantizer: Quantizer) -> Result<Gif, Error> {
+    engiffen_with_options(imgs, fps, &Options::from(quantizer))
+}
+
+/// Converts a sequence of images into a `Gif` at a given frame rate, using
+/// the palette and dithering settings in `options`.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use engiffen::{load_images, engiffen_with_options, Gif, Error, Options, Dither};
+/// # fn foo() -> Result<Gif, Error> {
+/// let paths = vec!["tests/ball/ball01.bmp", "tests/ball/ball02.bmp", "tests/ball/ball03.bmp"];
+/// let images = load_images(&paths);
+/// let options = Options { dither: Dither::Sierra, ..Options::default() };
+/// let gif = engiffen_with_options(&images, 10, &options)?;
+/// # Ok(gif)
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// If any image dimensions differ, this function will return an Error::Mismatch
+/// containing tuples of the conflicting image dimensions. An `fps` of 0 returns
+/// an Error::FrameRate.
+pub fn engiffen_with_options(imgs: &[Image], fps: usize, options: &Options) -> Result<Gif, Error> {
+    let delays = delays_for_fps(fps as f64, imgs.len())?;
+    engiffen_with_delays(imgs, &delays, options)
+}
+
+/// The longest delay a single frame can have, in milliseconds. Gifs store
+/// delays as a 16 bit count of hundredths of a second.
+pub const MAX_DELAY: u32 = 655_350;
+
+/// Computes the delays, in milliseconds, of `frames` frames played at `fps`
+/// frames per second. Fractional rates like 23.976 or 29.97 are allowed.
+///
+/// Gifs can only time frames in whole hundredths of a second, which most
+/// frame rates don't divide into evenly. Rather than rounding every frame the
+/// same way and letting the error add up, each frame ends on the hundredth of
+/// a second closest to when it should. At 30 fps, frames alternate between 30
+/// and 40 ms so that every 3 frames take exactly 100 ms.
+///
+/// # Examples
+///
+/// ```rust
+/// # use engiffen::delays_for_fps;
+/// assert_eq!(delays_for_fps(30.0, 6).unwrap(), vec![30, 40, 30, 30, 40, 30]);
+/// assert_eq!(delays_for_fps(25.0, 3).unwrap(), vec![40, 40, 40]);
+/// ```
+///
+/// # Errors
+///
+/// Returns an Error::FrameRate if `fps` isn't a finite number above 0.
+pub fn delays_for_fps(fps: f64, frames: usize) -> Result<Vec<u32>, Error> {
+    check_fps(fps)?;
+    Ok((0..frames).map(|n| frame_delay(fps, n)).collect())
+}
+
+/// Delay of the `n`th frame at `fps` frames per second, in milliseconds.
+fn frame_delay(fps: f64, n: usize) -> u32 {
+    let end_of = |frame: usize| (frame as f64 * 100.0 / fps).round();
+    ((end_of(n + 1) - end_of(n)) * 10.0) as u32
+}
+
+/// Returns an Error::FrameRate unless `fps` is a finite number above 0.
+fn check_fps(fps: f64) -> Result<(), Error> {
+    if fps.is_finite() && fps > 0.0 {
+        Ok(())
+    } else {
+        Err(Error::FrameRate(fps))
+    }
+}
+
+/// Returns an Error::DelayRange for the first delay longer than `MAX_DELAY`.
+fn check_delays(delays: &[u32]) -> Result<(), Error> {
+    match delays.iter().find(|&&delay| delay > MAX_DELAY) {
+        Some(&delay) => Err(Error::DelayRange(delay)),
+        None => Ok(()),
+    }
+}
+
+/// A delay in milliseconds rounded to the nearest hundredth of a second, as
+/// a Gif stores it. The delay must be no longer than `MAX_DELAY`.
+fn centiseconds(delay: u32) -> u16 {
+    ((delay + 5) / 10) as u16
+}
+
+/// Converts a sequence of images into a `Gif`, displaying each image for its
+/// own length of time. `delays` holds the duration of each frame in
+/// milliseconds, in the same order as `imgs`.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use engiffen::{load_images, engiffen_with_delays, Gif, Error, Options};
+/// # fn foo() -> Result<Gif, Error> {
+/// let paths = vec!["tests/ball/ball01.bmp", "tests/ball/ball02.bmp", "tests/ball/ball03.bmp"];
+/// let images = load_images(&paths);
+/// // Hold the first frame for two seconds, then show the rest at 25 fps
+/// let gif = engiffen_with_delays(&images, &[2000, 40, 40], &Options::default())?;
+/// assert_eq!(gif.delays, vec![2000, 40, 40]);
+/// # Ok(gif)
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// If any image dimensions differ, this function will return an Error::Mismatch
+/// containing tuples of the conflicting image dimensions. If the number of
+/// delays differs from the number of images, it returns an Error::DelayCount
+/// containing the number of images and the number of delays.
+/// If a delay is longer than `MAX_DELAY`, it returns an Error::DelayRange.
+pub fn engiffen_with_delays(imgs: &[Image], delays: &[u32], options: &Options) -> Result<Gif, Error> {
+    engiffen_with_quantizer(imgs, delays, &options.quantizer, options)
+}
+
+/// Converts a sequence of images into a `Gif` like `engiffen_with_delays`,
+/// but with a palette computed by any `Quantize` implementation instead of
+/// the `quantizer` in `options`. The rest of the `options` still apply.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use engiffen::{load_images, engiffen_with_quantizer, delays_for_fps, Gif, Error, Image, Options, Palette, Quantize};
+/// # fn foo() -> Result<Gif, Error> {
+/// struct Grayscale;
+///
+/// impl Quantize for Grayscale {
+///     fn palette(&self, _imgs: &[Image], _max_colors: usize) -> Palette {
+///         Palette {
+///             colors: (0..=255).flat_map(|v| vec![v, v, v]).collect(),
+///             transparency: None,
+///             index_of: Box::new(|px| ((px[0] as u32 * 3 + px[1] as u32 * 6 + px[2] as u32) / 10) as u8),
+///         }
+///     }
+/// }
+///
+/// let images = load_images(&["tests/ball/ball01.bmp", "tests/ball/ball02.bmp"]);
+/// let delays = delays_for_fps(10.0, images.len())?;
+/// let gif = engiffen_with_quantizer(&images, &delays, &Grayscale, &Options::default())?;
+/// # Ok(gif)
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// The same as `engiffen_with_delays`, and an Error::InvalidPalette if the
+/// quantizer's palette has no colors, more than `options` ask for (or more
+/// than 256 if it's fixed), a transparent index past its colors, or maps a
+/// color to an index past them.
+pub fn engiffen_with_quantizer<Q: Quantize + ?Sized>(imgs: &[Image], delays: &[u32], quantizer: &Q, options: &Options) -> Result<Gif, Error> {
     if imgs.is_empty() {
         return Err(Error::NoImages);
     }
+    if imgs.len() != delays.len() {
+        return Err(Error::DelayCount(imgs.len(), delays.len()));
+    }
+    check_delays(delays)?;
     #[cfg(feature = "debug-stderr")] eprintln!("Engiffening {} images", imgs.len());
 
-    let (width, height) = {
-        let ref first = imgs[0];
+    {
+        let first = &imgs[0];
         let first_dimensions = (first.width, first.height);
         for img in imgs.iter() {
             let other_dimensions = (img.width, img.height);
@@ -266,160 +898,839 @@ pub fn engiffen(imgs: &[Image], fps: usize, quantizer: Quantizer) -> Result<Gif,
                 return Err(Error::Mismatch(first_dimensions, other_dimensions));
             }
         }
-        first_dimensions
-    };
+    }
 
-    let (palette, palettized_imgs, transparency) = match quantizer {
-        Quantizer::NeuQuant(sample_rate) => neuquant_palettize(&imgs, sample_rate, width, height),
-        Quantizer::Naive => naive_palettize(&imgs),
+    let flattened_imgs: Vec<Image>;
+    let imgs = if options.flattens() {
+        flattened_imgs = imgs.par_iter().map(|img| flattened(img, options)).collect();
+        &flattened_imgs[..]
+    } else {
+        imgs
     };
 
-    let delay = (1000 / fps) as u16;
-
-    Ok(Gif {
-        palette: palette,
-        transparency: transparency,
-        width: width as u16,
-        height: height as u16,
-        images: palettized_imgs,
-        delay: delay,
-    })
+    let palette = quantizer.palette(imgs, options.max_colors());
+    let group_palette = |range: Range<usize>| quantizer.palette(&imgs[range], options.max_colors());
+    compile(imgs, delays.to_vec(), palette, group_palette, quantizer.is_fixed(), options)
 }
 
-fn neuquant_palettize(imgs: &[Image], sample_rate: u32, width: u32, height: u32) -> (Vec<u8>, Vec<Vec<u8>>, Option<u8>) {
-    let image_len = (width * height * 4 / sample_rate / sample_rate) as usize;
-    let width = width as usize;
-    let sample_rate = sample_rate as usize;
-    let transparent_black = [0u8; 4];
-    #[cfg(feature = "debug-stderr")] let time_push = Instant::now();
-    let colors: Vec<u8> = imgs.par_iter().map(|img| {
-        let mut temp: Vec<_> = Vec::with_capacity(image_len);
-        for (n, px) in img.pixels.iter().enumerate() {
-            if sample_rate > 1 {
-                if n % sample_rate != 0 || (n / width) % sample_rate != 0 {
-                    continue;
-                }
+/// Copy of the image as a Gif can show it. Pixels less opaque than the
+/// options' alpha threshold are made fully transparent, and the rest fully
+/// opaque, after blending them over the matte if there is one.
+fn flattened(img: &Image, options: &Options) -> Image {
+    let width = img.width.max(1) as usize;
+    Image {
+        pixels: img.pixels.iter().enumerate().map(|(n, px)| {
+            if px[3] < options.alpha_threshold {
+                return [0, 0, 0, 0];
             }
-            if px[3] == 0 {
-                temp.extend_from_slice(&transparent_black);
-            } else {
-                temp.extend_from_slice(&px[..3]);
-                temp.push(255);
+            match options.matte {
+                Some(ref matte) if px[3] < 255 => {
+                    let background = matte.color_at(n % width, n / width);
+                    let alpha = px[3] as u32;
+                    let blend = |c: usize| ((px[c] as u32 * alpha + background[c] as u32 * (255 - alpha) + 127) / 255) as u8;
+                    [blend(0), blend(1), blend(2), 255]
+                },
+                _ => [px[0], px[1], px[2], 255],
             }
-        }
-        temp
-    }).reduce(|| Vec::with_capacity(image_len * imgs.len()), |mut acc, img| {
-        acc.extend_from_slice(&img);
-        acc
-    });
-    #[cfg(feature = "debug-stderr")]
-    eprintln!("Neuquant: Concatenated {} bytes in {} ms.", colors.len(), ms(time_push));
-
-    #[cfg(feature = "debug-stderr")] let time_quant = Instant::now();
-    let quant = NeuQuant::new(10, 256, &colors);
-    #[cfg(feature = "debug-stderr")]
-    eprintln!("Neuquant: Computed palette in {} ms.", ms(time_quant));
+        }).collect(),
+        width: img.width,
+        height: img.height,
+    }
+}
 
-    #[cfg(feature = "debug-stderr")] let time_map = Instant::now();
-    let mut transparency = None;
-    let mut cache: FnvHashMap<RGBA, u8> = FnvHashMap::default();
-    let palettized_imgs: Vec<Vec<u8>> = imgs.iter().map(|img| {
-        img.pixels.iter().map(|px| {
-            *cache.entry(*px).or_insert_with(|| {
-                let idx = quant.index_of(px) as u8;
-                if transparency.is_none() && px[3] == 0 {
-                    transparency = Some(idx);
-                }
-                idx
-            })
-        }).collect()
-    }).collect();
-    #[cfg(feature = "debug-stderr")]
-    eprintln!("Neuquant: Mapped pixels to palette in {} ms.", ms(time_map));
+/// Incrementally converts a stream of images into a `Gif`.
+///
+/// Unlike `engiffen`, which needs every frame up front, an `Encoder` accepts
+/// frames one at a time as they become available. Color statistics for the
+/// palette are gathered from each frame as it's pushed, but the palette itself
+/// isn't computed until `finish` is called.
+///
+/// Frames can only be mapped onto the palette once it's known, so until then
+/// each one is held compressed without loss, and `finish` unpacks them one at
+/// a time. How much memory that saves depends on the frames: rendered scenes
+/// and screen recordings with large flat areas shrink the most, while noisy
+/// footage shrinks the least.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use engiffen::{load_image, Encoder, Gif, Error, Quantizer};
+/// # fn foo() -> Result<Gif, Error> {
+/// let mut encoder = Encoder::new(10.0, Quantizer::NeuQuant(2))?;
+/// for path in &["tests/ball/ball01.bmp", "tests/ball/ball02.bmp", "tests/ball/ball03.bmp"] {
+///     encoder.push(load_image(path)?)?;
+/// }
+/// let gif = encoder.finish()?;
+/// assert_eq!(gif.images.len(), 3);
+/// # Ok(gif)
+/// # }
+/// ```
+pub struct Encoder {
+    fps: f64,
+    options: Options,
+    frames: Vec<Packed>,
+    delays: Vec<u32>,
+    stats: PaletteStats,
+}
 
-    (quant.color_map_rgb(), palettized_imgs, transparency)
+impl fmt::Debug for Encoder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Encoder {{ fps: {:?}, frames: Vec<Packed x {:?}> }}", self.fps, self.frames.len())
+    }
 }
 
-fn naive_palettize(imgs: &[Image]) -> (Vec<u8>, Vec<Vec<u8>>, Option<u8>) {
-    #[cfg(feature = "debug-stderr")] let time_count = Instant::now();
-    let frequencies: FnvHashMap<RGBA, usize> = imgs.par_iter().map(|img| {
-        let mut fr: FnvHashMap<RGBA, usize> = FnvHashMap::default();
-        for pixel in img.pixels.iter() {
-            let num = fr.entry(*pixel).or_insert(0);
-            *num += 1;
-        }
-        fr
-    }).reduce(|| FnvHashMap::default(), |mut acc, fr| {
-        for (color, count) in fr {
-            let num = acc.entry(color).or_insert(0);
-            *num += count;
-        }
-        acc
-    });
-    #[cfg(feature = "debug-stderr")]
-    eprintln!("Naive: Counted color frequencies in {} ms", ms(time_count));
-    #[cfg(feature = "debug-stderr")] let time_palette = Instant::now();
-    let mut sorted_frequencies = frequencies.into_iter()
-        .collect::<Vec<_>>();
-    sorted_frequencies.sort_by(|a, b| b.1.cmp(&a.1));
-    let sorted = sorted_frequencies.into_iter().map(|c| {
-        (c.0, Lab::from_rgba(&c.0))
-    }).collect::<Vec<_>>();
+impl Encoder {
+    /// Creates an encoder for a `Gif` at the given frame rate, using `quantizer`
+    /// to compute its palette. Fractional rates like 29.97 are allowed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an Error::FrameRate if `fps` isn't a finite number above 0.
+    pub fn new(fps: f64, quantizer: Quantizer) -> Result<Encoder, Error> {
+        Encoder::with_options(fps, Options::from(quantizer))
+    }
 
-    let (palette, rest) = if sorted.len() > 256 {
-        (&sorted[..256], &sorted[256..])
-    } else {
-        (&sorted[..], &[] as &[_])
-    };
+    /// Creates an encoder for a `Gif` at the given frame rate, using the
+    /// palette and dithering settings in `options`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an Error::FrameRate if `fps` isn't a finite number above 0.
+    pub fn with_options(fps: f64, options: Options) -> Result<Encoder, Error> {
+        check_fps(fps)?;
+        Ok(Encoder {
+            fps,
+            stats: PaletteStats::new(options.quantizer),
+            options,
+            frames: Vec::new(),
+            delays: Vec::new(),
+        })
+    }
 
-    let mut map: FnvHashMap<RGBA, u8> = FnvHashMap::default();
+    /// Adds the next frame of the `Gif`, displayed for the duration of one
+    /// frame at the encoder's frame rate.
+    ///
+    /// # Errors
+    ///
+    /// If the image's dimensions differ from the first frame's, the image is
+    /// rejected with an Error::Mismatch containing tuples of the conflicting
+    /// image dimensions.
+    pub fn push(&mut self, img: Image) -> Result<(), Error> {
+        let delay = frame_delay(self.fps, self.frames.len());
+        self.push_with_delay(img, delay)
+    }
+
+    /// Adds the next frame of the `Gif`, displayed for `delay` milliseconds.
+    ///
+    /// # Errors
+    ///
+    /// If the image's dimensions differ from the first frame's, the image is
+    /// rejected with an Error::Mismatch containing tuples of the conflicting
+    /// image dimensions. A `delay` over `MAX_DELAY` is rejected with an
+    /// Error::DelayRange.
+    pub fn push_with_delay(&mut self, img: Image, delay: u32) -> Result<(), Error> {
+        check_delays(&[delay])?;
+        if !self.frames.is_empty() {
+            let first_dimensions = self.frames[..].dimensions();
+            let other_dimensions = (img.width, img.height);
+            if first_dimensions != other_dimensions {
+                return Err(Error::Mismatch(first_dimensions, other_dimensions));
+            }
+        }
+        let img = if self.options.flattens() {
+            flattened(&img, &self.options)
+        } else {
+            img
+        };
+        self.frames.push(Packed::new(&img)?);
+        self.stats.add(&img);
+        self.delays.push(delay);
+        Ok(())
+    }
+
+    /// Number of frames pushed so far.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Whether no frames have been pushed yet.
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Computes the palette from every pushed frame and compiles the `Gif`.
+    ///
+    /// # Errors
+    ///
+    /// Returns Error::NoImages if no frames were pushed.
+    pub fn finish(self) -> Result<Gif, Error> {
+        if self.frames.is_empty() {
+            return Err(Error::NoImages);
+        }
+        #[cfg(feature = "debug-stderr")] eprintln!("Engiffening {} images", self.frames.len());
+        let (frames, options) = (&self.frames[..], &self.options);
+        let palette = self.stats.palette(options.max_colors());
+        let group_palette = |range: Range<usize>| options.quantizer.stats(frames, range).palette(options.max_colors());
+        compile(frames, self.delays, palette, group_palette, false, options)
+    }
+}
+
+/// Maps every frame onto the palette, refining it first if the options ask
+/// for that, and gives groups of frames palettes of their own from
+/// `group_palette` if the palette mode calls for it. `fixed` is whether the
+/// quantizer's palettes are fixed. Frames must be non-empty and share the
+/// same dimensions, and are read one at a time. Duplicate frames are dropped
+/// last if the options ask for that.
+///
+/// Returns an Error::InvalidPalette if a palette from the quantizer couldn't
+/// be written to a Gif, or maps a color to an index past its colors.
+fn compile<S, F>(imgs: &S, mut delays: Vec<u32>, palette: Palette, group_palette: F, fixed: bool, options: &Options) -> Result<Gif, Error>
+    where S: Source + ?Sized, F: Fn(Range<usize>) -> Palette {
+    let all = 0..imgs.count();
+    let (palette, bad_index) = checked(refine(imgs, all.clone(), palette, fixed, options), fixed, options)?;
+    let mut palettized_imgs = palettize(imgs, all.clone(), &palette, options);
+    check_indices(&bad_index, &palette)?;
+
+    // Ranges of frames that could share a palette of their own
+    let groups: Vec<Range<usize>> = match options.palette_mode {
+        PaletteMode::Global => Vec::new(),
+        PaletteMode::PerFrame | PaletteMode::Auto => all.clone().map(|n| n..n + 1).collect(),
+        PaletteMode::PerScene => {
+            let scenes = scenes::split(imgs);
+            if scenes.len() > 1 { scenes } else { Vec::new() }
+        },
+    };
+
+    let mut local_palettes = vec![None; all.len()];
+    #[cfg(feature = "debug-stderr")] let time_local = Instant::now();
+    for range in groups {
+        let local = refine(imgs, range.clone(), group_palette(range.clone()), fixed, options);
+        let (local, bad_index) = checked(local, fixed, options)?;
+        let local_imgs = palettize(imgs, range.clone(), &local, options);
+        check_indices(&bad_index, &local)?;
+        let use_local = options.palette_mode != PaletteMode::Auto || {
+            let global_error = group_error(imgs, range.clone(), &palettized_imgs[range.clone()], &palette);
+            let local_error = group_error(imgs, range.clone(), &local_imgs, &local);
+            global_error > LOCAL_PALETTE_MIN_ERROR && local_error < global_error * LOCAL_PALETTE_GAIN
+        };
+        if use_local {
+            for (n, local_img) in range.zip(local_imgs) {
+                palettized_imgs[n] = local_img;
+                local_palettes[n] = Some(LocalPalette {
+                    colors: local.colors.clone(),
+                    transparency: local.transparency,
+                });
+            }
+        }
+    }
+    #[cfg(feature = "debug-stderr")]
+    eprintln!("Computed local palettes in {} ms.", ms(time_local));
+
+    if options.dedupe {
+        deduplicate(&mut palettized_imgs, &mut delays, &mut local_palettes);
+    }
+    let frames = palettized_imgs.len();
+    let (width, height) = imgs.dimensions();
+
+    Ok(Gif {
+        palette: palette.colors,
+        transparency: palette.transparency,
+        width: width as u16,
+        height: height as u16,
+        images: palettized_imgs,
+        delays,
+        looping: Loop::Infinite,
+        disposals: vec![Disposal::Auto; frames],
+        local_palettes,
+        optimize: false,
+        lossy: 0,
+    })
+}
+
+/// The first index past its palette's colors that an `index_of` wrapped by
+/// `checked` returned, if there was one.
+type BadIndex = Arc<Mutex<Option<u8>>>;
+
+/// Checks that a palette from a quantizer can be written to a Gif: it has
+/// whole colors, at least one and no more than 256, no more than the options
+/// ask for unless the palette is `fixed`, and a transparent index among them.
+/// Its `index_of` is wrapped to map any index past the colors to 0 instead,
+/// and to keep the first such index for `check_indices`.
+fn checked(palette: Palette, fixed: bool, options: &Options) -> Result<(Palette, BadIndex), Error> {
+    let len = palette.colors.len() / 3;
+    let max_colors = if fixed { 256 } else { options.max_colors() };
+    if !palette.colors.len().is_multiple_of(3) {
+        return Err(Error::InvalidPalette(format!("its {} bytes aren't a whole number of colors", palette.colors.len())));
+    }
+    if len == 0 || len > max_colors {
+        return Err(Error::InvalidPalette(format!("it has {} colors, but needs from 1 to {}", len, max_colors)));
+    }
+    if let Some(index) = palette.transparency.filter(|&index| index as usize >= len) {
+        return Err(Error::InvalidPalette(format!("its transparent index {} is past its {} colors", index, len)));
+    }
+
+    let bad_index = Arc::new(Mutex::new(None));
+    let found = bad_index.clone();
+    let index_of = palette.index_of;
+    let checked_index_of = move |px: &RGBA| {
+        let index = index_of(px);
+        if (index as usize) < len {
+            return index;
+        }
+        if let Ok(mut found) = found.lock() {
+            found.get_or_insert(index);
+        }
+        0
+    };
+    Ok((Palette {
+        colors: palette.colors,
+        transparency: palette.transparency,
+        index_of: Box::new(checked_index_of),
+    }, bad_index))
+}
+
+/// Returns an Error::InvalidPalette if the `index_of` wrapped by `checked`
+/// mapped any color past the palette's colors.
+fn check_indices(bad_index: &Mutex<Option<u8>>, palette: &Palette) -> Result<(), Error> {
+    match bad_index.lock().ok().and_then(|found| *found) {
+        Some(index) => Err(Error::InvalidPalette(format!("it mapped a color to index {}, past its {} colors", index, palette.colors.len() / 3))),
+        None => Ok(()),
+    }
+}
+
+/// Drops frames whose indices and local palette are the same as the frame's
+/// before them, adding their delays to the frame that's kept. Once the kept
+/// frame holds `MAX_DELAY`, the rest of the run goes to the next frame kept.
+fn deduplicate(imgs: &mut Vec<Vec<u8>>, delays: &mut Vec<u32>, local_palettes: &mut Vec<Option<LocalPalette>>) {
+    let mut kept = 0;
+    for n in 1..imgs.len() {
+        let same = imgs[n] == imgs[kept] && local_palettes[n] == local_palettes[kept];
+        let total = delays[kept] + delays[n];
+        if same && total <= MAX_DELAY {
+            delays[kept] = total;
+        } else {
+            if same {
+                delays[kept] = MAX_DELAY;
+                delays[n] = total - MAX_DELAY;
+            }
+            kept += 1;
+            imgs.swap(kept, n);
+            delays.swap(kept, n);
+            local_palettes.swap(kept, n);
+        }
+    }
+    let frames = imgs.len().min(kept + 1);
+    imgs.truncate(frames);
+    delays.truncate(frames);
+    local_palettes.truncate(frames);
+}
+
+/// Color statistics gathered from frames, from which a palette is computed
+/// once every frame has been seen.
+///
+/// `NeuQuant` holds the sample rate, a random sample of the sampled opaque
+/// pixels for the network to train on, and whether any pixels were
+/// transparent. `Naive` and
+/// `MedianCut` hold the frequency of every opaque color, `Octree` and `Wu`
+/// the tree or histogram of them, and all four whether any pixels were
+/// transparent. Partly transparent pixels count as the opaque color with
+/// the same red, green and blue.
+enum PaletteStats {
+    NeuQuant(u32, Reservoir<Rgb>, bool),
+    Naive(FnvHashMap<Rgb, usize>, bool),
+    MedianCut(FnvHashMap<Rgb, usize>, bool),
+    Octree(octree::Octree, bool),
+    Wu(Box<wu::Histogram>, bool),
+}
+
+/// Most leaves the octree keeps while gathering colors, before it's reduced
+/// to the palette size.
+const OCTREE_LEAVES: usize = 1024;
+
+/// Most pixels kept for NeuQuant to train on, however many frames there are.
+const NEUQUANT_SAMPLES: usize = 1 << 20;
+
+impl PaletteStats {
+    fn new(quantizer: Quantizer) -> PaletteStats {
+        match quantizer {
+            Quantizer::NeuQuant(sample_rate) => PaletteStats::NeuQuant(sample_rate, Reservoir::new(NEUQUANT_SAMPLES), false),
+            Quantizer::Naive => PaletteStats::Naive(FnvHashMap::default(), false),
+            Quantizer::MedianCut => PaletteStats::MedianCut(FnvHashMap::default(), false),
+            Quantizer::Octree => PaletteStats::Octree(octree::Octree::new(OCTREE_LEAVES), false),
+            Quantizer::Wu => PaletteStats::Wu(Box::new(wu::Histogram::new()), false),
+        }
+    }
+
+    fn add(&mut self, img: &Image) {
+        match *self {
+            PaletteStats::NeuQuant(sample_rate, ref mut colors, ref mut has_transparency) => {
+                neuquant_sample(img, sample_rate, colors);
+                *has_transparency = *has_transparency || img.pixels.iter().any(|px| px[3] == 0);
+            },
+            PaletteStats::Naive(ref mut frequencies, ref mut has_transparency) |
+            PaletteStats::MedianCut(ref mut frequencies, ref mut has_transparency) => {
+                *has_transparency |= add_opaque(img, |color| *frequencies.entry(color).or_insert(0) += 1);
+            },
+            PaletteStats::Octree(ref mut tree, ref mut has_transparency) => {
+                *has_transparency |= add_opaque(img, |color| tree.add(color));
+            },
+            PaletteStats::Wu(ref mut histogram, ref mut has_transparency) => {
+                *has_transparency |= add_opaque(img, |color| histogram.add(color));
+            },
+        }
+    }
+
+    fn merge(self, other: PaletteStats) -> PaletteStats {
+        match (self, other) {
+            (PaletteStats::NeuQuant(sample_rate, colors, has_transparency), PaletteStats::NeuQuant(_, other_colors, other_has_transparency)) => {
+                PaletteStats::NeuQuant(sample_rate, colors.merge(other_colors), has_transparency || other_has_transparency)
+            },
+            (PaletteStats::Naive(frequencies, has_transparency), PaletteStats::Naive(other_frequencies, other_has_transparency)) => {
+                PaletteStats::Naive(merge_frequencies(frequencies, other_frequencies), has_transparency || other_has_transparency)
+            },
+            (PaletteStats::MedianCut(frequencies, has_transparency), PaletteStats::MedianCut(other_frequencies, other_has_transparency)) => {
+                PaletteStats::MedianCut(merge_frequencies(frequencies, other_frequencies), has_transparency || other_has_transparency)
+            },
+            (PaletteStats::Octree(mut tree, has_transparency), PaletteStats::Octree(other_tree, other_has_transparency)) => {
+                tree.merge(other_tree);
+                PaletteStats::Octree(tree, has_transparency || other_has_transparency)
+            },
+            (PaletteStats::Wu(mut histogram, has_transparency), PaletteStats::Wu(other_histogram, other_has_transparency)) => {
+                histogram.merge(&other_histogram);
+                PaletteStats::Wu(histogram, has_transparency || other_has_transparency)
+            },
+            _ => unreachable!("Color statistics from different quantizers can't be merged."),
+        }
+    }
+
+    fn palette(self, max_colors: usize) -> Palette {
+        match self {
+            PaletteStats::NeuQuant(_, colors, has_transparency) => neuquant_palette(colors.items(), has_transparency, max_colors),
+            PaletteStats::Naive(frequencies, has_transparency) => naive_palette(frequencies, has_transparency, max_colors),
+            PaletteStats::MedianCut(frequencies, has_transparency) => median_cut_palette(frequencies, has_transparency, max_colors),
+            PaletteStats::Octree(tree, has_transparency) => octree_palette(tree, has_transparency, max_colors),
+            PaletteStats::Wu(histogram, has_transparency) => wu_palette(*histogram, has_transparency, max_colors),
+        }
+    }
+}
+
+/// Maps the pixels of the frames in `range` to their palette indices,
+/// dithering them if the options ask for that.
+fn palettize<S: Source + ?Sized>(imgs: &S, range: Range<usize>, palette: &Palette, options: &Options) -> Vec<Vec<u8>> {
+    #[cfg(feature = "debug-stderr")] let time_map = Instant::now();
+    let index_of = &palette.index_of;
+    let threshold_map = dither::ThresholdMap::new(options.dither);
+    let palettized_imgs: Vec<Vec<u8>> = range.into_par_iter().map(|n| {
+        let img = imgs.frame(n);
+        let img = &*img;
+        if let Some(ref map) = threshold_map {
+            return dither::ordered(img, palette.colors.len() / 3, &**index_of, map, options.dither_strength);
+        }
+        match options.dither {
+            Dither::None => {
+                let mut cache: FnvHashMap<RGBA, u8> = FnvHashMap::default();
+                img.pixels.iter().map(|px| {
+                    *cache.entry(*px).or_insert_with(|| index_of(px))
+                }).collect()
+            },
+            dither => dither::diffuse(img, &palette.colors, &**index_of, dither, options.dither_strength, options.serpentine),
+        }
+    }).collect();
+    #[cfg(feature = "debug-stderr")]
+    eprintln!("Mapped pixels to palette in {} ms.", ms(time_map));
+
+    palettized_imgs
+}
+
+/// Passes the color of every pixel of `img` that isn't fully transparent to
+/// `add`, and returns whether any pixels were.
+fn add_opaque<F: FnMut(Rgb)>(img: &Image, mut add: F) -> bool {
+    let mut has_transparency = false;
+    for px in &img.pixels {
+        if px[3] == 0 {
+            has_transparency = true;
+        } else {
+            add([px[0], px[1], px[2]]);
+        }
+    }
+    has_transparency
+}
+
+/// Adds the opaque pixels on every `sample_rate`th column of every
+/// `sample_rate`th row of `img` to the sample.
+fn neuquant_sample(img: &Image, sample_rate: u32, colors: &mut Reservoir<Rgb>) {
+    let width = img.width as usize;
+    let sample_rate = sample_rate.max(1) as usize;
+    for (n, px) in img.pixels.iter().enumerate() {
+        if !n.is_multiple_of(sample_rate) || !(n / width).is_multiple_of(sample_rate) {
+            continue;
+        }
+        if px[3] != 0 {
+            colors.add([px[0], px[1], px[2]]);
+        }
+    }
+}
+
+/// NeuQuant palette for the sampled opaque pixels. If any pixels were fully
+/// transparent, they get a palette entry of their own, which is returned as
+/// the transparent index.
+fn neuquant_palette(colors: &[Rgb], has_transparency: bool, max_colors: usize) -> Palette {
+    #[cfg(feature = "debug-stderr")] let time_quant = Instant::now();
+    let mut pixels: Vec<u8> = colors.iter().flat_map(|c| vec![c[0], c[1], c[2], 255]).collect();
+    // The network needs something to train on, even if every pixel is
+    // transparent
+    if pixels.is_empty() {
+        pixels = vec![0, 0, 0, 255];
+    }
+    let quant = NeuQuant::new(10, opaque_colors(max_colors, has_transparency), &pixels);
+    #[cfg(feature = "debug-stderr")]
+    eprintln!("Neuquant: Computed palette in {} ms.", ms(time_quant));
+
+    let opaque: Vec<Rgb> = quant.color_map_rgb().chunks(3).map(|c| [c[0], c[1], c[2]]).collect();
+    let (palette_as_bytes, transparency) = palette_bytes(&opaque, has_transparency);
+    let index_of = move |px: &RGBA| {
+        match transparency {
+            Some(index) if px[3] == 0 => index,
+            _ => quant.index_of(&[px[0], px[1], px[2], 255]) as u8,
+        }
+    };
+
+    Palette {
+        colors: palette_as_bytes,
+        transparency,
+        index_of: Box::new(index_of),
+    }
+}
+
+/// Palette of the most frequent opaque colors. If any pixels were fully
+/// transparent, they get a palette entry of their own after those colors,
+/// which is returned as the transparent index.
+fn naive_palette(frequencies: FnvHashMap<Rgb, usize>, has_transparency: bool, max_colors: usize) -> Palette {
+    #[cfg(feature = "debug-stderr")] let time_palette = Instant::now();
+    let max_colors = opaque_colors(max_colors, has_transparency);
+    let mut sorted_frequencies = frequencies.into_iter().collect::<Vec<_>>();
+    sorted_frequencies.sort_by_key(|&(_, count)| Reverse(count));
+    let sorted = sorted_frequencies.into_iter().map(|(c, _)| {
+        let rgba = [c[0], c[1], c[2], 255];
+        (rgba, Lab::from_rgba(&rgba))
+    }).collect::<Vec<_>>();
+
+    let (palette, rest) = if sorted.len() > max_colors {
+        (&sorted[..max_colors], &sorted[max_colors..])
+    } else {
+        (&sorted[..], &[] as &[_])
+    };
+
+    let rgb = |rgba: &RGBA| [rgba[0], rgba[1], rgba[2]];
+    let mut map: FnvHashMap<Rgb, u8> = FnvHashMap::default();
     for (i, color) in palette.iter().enumerate() {
-        map.insert(color.0, i as u8);
+        map.insert(rgb(&color.0), i as u8);
     }
     for color in rest {
-        let closest_index = palette.iter().enumerate().fold((0, f32::INFINITY), |closest, (idx, p)| {
-            let dist = p.1.squared_distance(&color.1);
-            if closest.1 < dist {
-                closest
-            } else {
-                (idx, dist)
-            }
-        }).0;
-        let closest_rgb = palette[closest_index].0;
+        let closest_rgb = rgb(&palette[closest_lab(palette, &color.1)].0);
         let index = *map.get(&closest_rgb).expect("A color we assigned to the palette is somehow missing from the palette index map.");
-        map.insert(color.0, index);
+        map.insert(rgb(&color.0), index);
     }
     #[cfg(feature = "debug-stderr")]
     eprintln!("Naive: Computed palette in {} ms.", ms(time_palette));
 
-    #[cfg(feature = "debug-stderr")]let time_index = Instant::now();
-    let palettized_imgs: Vec<Vec<u8>> = imgs.par_iter().map(|img| {
-        img.pixels.iter().map(|px| {
-            *map.get(px).expect("A color in an image was not added to the palette map.")
-        }).collect()
+    let colors: Vec<Rgb> = palette.iter().map(|c| rgb(&c.0)).collect();
+    let (palette_as_bytes, transparency) = palette_bytes(&colors, has_transparency);
+
+    // Dithering asks for colors that never appeared in the frames, so look
+    // those up by distance instead of in the map.
+    let palette = palette.to_vec();
+    let index_of = move |px: &RGBA| {
+        if px[3] == 0 {
+            if let Some(index) = transparency {
+                return index;
+            }
+        }
+        match map.get(&[px[0], px[1], px[2]]) {
+            Some(index) => *index,
+            None => closest_lab(&palette, &Lab::from_rgba(px)) as u8,
+        }
+    };
+
+    Palette {
+        colors: palette_as_bytes,
+        transparency,
+        index_of: Box::new(index_of),
+    }
+}
+
+fn merge_frequencies(mut frequencies: FnvHashMap<Rgb, usize>, other: FnvHashMap<Rgb, usize>) -> FnvHashMap<Rgb, usize> {
+    for (color, count) in other {
+        *frequencies.entry(color).or_insert(0) += count;
+    }
+    frequencies
+}
+
+/// Median cut palette for the frequencies of opaque colors. If any pixels
+/// were fully transparent, they get a palette entry of their own, which is
+/// returned as the transparent index.
+fn median_cut_palette(frequencies: FnvHashMap<Rgb, usize>, has_transparency: bool, max_colors: usize) -> Palette {
+    #[cfg(feature = "debug-stderr")] let time_palette = Instant::now();
+    let histogram: Vec<(Rgb, usize)> = frequencies.into_iter().collect();
+    let (colors, indices) = median_cut::quantize(&histogram, opaque_colors(max_colors, has_transparency));
+    #[cfg(feature = "debug-stderr")]
+    eprintln!("Median cut: Computed palette in {} ms.", ms(time_palette));
+
+    let (palette_as_bytes, transparency) = palette_bytes(&colors, has_transparency);
+    let mut map: FnvHashMap<Rgb, u8> = FnvHashMap::default();
+    for (entry, &index) in histogram.iter().zip(indices.iter()) {
+        map.insert(entry.0, index);
+    }
+
+    let palette: Vec<(RGBA, Lab)> = colors.iter().map(|c| {
+        let rgba = [c[0], c[1], c[2], 255];
+        (rgba, Lab::from_rgba(&rgba))
+    }).collect();
+    let index_of = move |px: &RGBA| {
+        if px[3] == 0 {
+            if let Some(index) = transparency {
+                return index;
+            }
+        }
+        match map.get(&[px[0], px[1], px[2]]) {
+            Some(index) => *index,
+            None => closest_lab(&palette, &Lab::from_rgba(px)) as u8,
+        }
+    };
+
+    Palette {
+        colors: palette_as_bytes,
+        transparency,
+        index_of: Box::new(index_of),
+    }
+}
+
+/// Octree palette for the gathered colors. If any pixels were fully
+/// transparent, they get a palette entry of their own, which is returned as
+/// the transparent index.
+fn octree_palette(mut tree: octree::Octree, has_transparency: bool, max_colors: usize) -> Palette {
+    #[cfg(feature = "debug-stderr")] let time_palette = Instant::now();
+    let colors = tree.palette(opaque_colors(max_colors, has_transparency));
+    #[cfg(feature = "debug-stderr")]
+    eprintln!("Octree: Computed palette in {} ms.", ms(time_palette));
+
+    let (palette_as_bytes, transparency) = palette_bytes(&colors, has_transparency);
+
+    let palette: Vec<(RGBA, Lab)> = colors.iter().map(|c| {
+        let rgba = [c[0], c[1], c[2], 255];
+        (rgba, Lab::from_rgba(&rgba))
     }).collect();
+    let index_of = move |px: &RGBA| {
+        if px[3] == 0 {
+            if let Some(index) = transparency {
+                return index;
+            }
+        }
+        match tree.index_of(&[px[0], px[1], px[2]]) {
+            Some(index) => index,
+            None => closest_lab(&palette, &Lab::from_rgba(px)) as u8,
+        }
+    };
+
+    Palette {
+        colors: palette_as_bytes,
+        transparency,
+        index_of: Box::new(index_of),
+    }
+}
+
+/// Wu palette for the gathered colors. If any pixels were fully transparent,
+/// they get a palette entry of their own, which is returned as the
+/// transparent index.
+fn wu_palette(histogram: wu::Histogram, has_transparency: bool, max_colors: usize) -> Palette {
+    #[cfg(feature = "debug-stderr")] let time_palette = Instant::now();
+    let palette = histogram.palette(opaque_colors(max_colors, has_transparency));
     #[cfg(feature = "debug-stderr")]
-    eprintln!("Naive: Mapped pixels to palette in {} ms", ms(time_index));
+    eprintln!("Wu: Computed palette in {} ms.", ms(time_palette));
+
+    let (palette_as_bytes, transparency) = palette_bytes(&palette.colors, has_transparency);
+    let index_of = move |px: &RGBA| {
+        match transparency {
+            Some(index) if px[3] == 0 => index,
+            _ => palette.index_of(&[px[0], px[1], px[2]]),
+        }
+    };
 
-    let mut palette_as_bytes = Vec::with_capacity(palette.len() * 3);
-    for color in palette {
-        palette_as_bytes.extend_from_slice(&color.0[0..3]);
+    Palette {
+        colors: palette_as_bytes,
+        transparency,
+        index_of: Box::new(index_of),
     }
+}
+
+/// Mean squared error below which a frame already looks right with the
+/// global palette, so `PaletteMode::Auto` doesn't give it one of its own.
+const LOCAL_PALETTE_MIN_ERROR: f64 = 4.0;
 
-    (palette_as_bytes, palettized_imgs, None)
+/// How much of a frame's error with the global palette its own palette has
+/// to leave for `PaletteMode::Auto` to spend a local color table on it.
+const LOCAL_PALETTE_GAIN: f64 = 0.75;
+
+/// Mean of `mapping_error` over a group of frames.
+fn group_error<S: Source + ?Sized>(imgs: &S, range: Range<usize>, palettized_imgs: &[Vec<u8>], palette: &Palette) -> f64 {
+    let count = range.len();
+    let total: f64 = range.zip(palettized_imgs.iter())
+        .map(|(n, indices)| mapping_error(&imgs.frame(n), indices, &palette.colors, palette.transparency))
+        .sum();
+    total / count.max(1) as f64
+}
+
+/// Mean squared difference between the opaque pixels of an image and the
+/// palette colors they were mapped to.
+fn mapping_error(img: &Image, indices: &[u8], colors: &[u8], transparency: Option<u8>) -> f64 {
+    let mut total = 0f64;
+    let mut count = 0usize;
+    for (px, &idx) in img.pixels.iter().zip(indices.iter()) {
+        if px[3] == 0 || Some(idx) == transparency {
+            continue;
+        }
+        let i = idx as usize * 3;
+        let rgb = match colors.get(i..i + 3) {
+            Some(rgb) => rgb,
+            None => continue,
+        };
+        for (&p, &c) in px.iter().zip(rgb.iter()) {
+            let diff = p as f64 - c as f64;
+            total += diff * diff;
+        }
+        count += 1;
+    }
+    if count == 0 { 0.0 } else { total / count as f64 }
+}
+
+/// The palette refined with k-means over the frames in `range` if the
+/// options ask for that, unless the palette is `fixed`.
+fn refine<S: Source + ?Sized>(imgs: &S, range: Range<usize>, palette: Palette, fixed: bool, options: &Options) -> Palette {
+    if options.kmeans > 0 && !fixed {
+        refined_palette(imgs, range, &palette, options.kmeans)
+    } else {
+        palette
+    }
+}
+
+/// Most pixels sampled from all frames for k-means refinement.
+const KMEANS_SAMPLES: usize = 100_000;
+
+/// Refines the palette with k-means in Lab space over pixels sampled evenly
+/// from the frames in `range`, leaving the transparent entry alone.
+fn refined_palette<S: Source + ?Sized>(imgs: &S, range: Range<usize>, palette: &Palette, iterations: u32) -> Palette {
+    #[cfg(feature = "debug-stderr")] let time_refine = Instant::now();
+    let (width, height) = imgs.dimensions();
+    let frame_len = width as usize * height as usize;
+    let stride = (range.len() * frame_len).div_ceil(KMEANS_SAMPLES).max(1);
+    let start = range.start;
+    // Every `stride`th pixel, counting on from one frame to the next
+    let samples: Vec<Vec<Lab>> = range.into_par_iter().map(|n| {
+        let offset = (n - start) * frame_len;
+        imgs.frame(n).pixels.iter()
+            .skip((stride - offset % stride) % stride)
+            .step_by(stride)
+            .filter(|px| px[3] != 0)
+            .map(Lab::from_rgba)
+            .collect()
+    }).collect();
+    let samples: Vec<Lab> = samples.into_iter().flat_map(|frame| frame.into_iter()).collect();
+
+    let transparency = palette.transparency;
+    let (indices, colors): (Vec<u8>, Vec<Rgb>) = palette.colors.chunks(3)
+        .enumerate()
+        .filter(|&(n, _)| Some(n as u8) != transparency)
+        .map(|(n, c)| (n as u8, [c[0], c[1], c[2]]))
+        .unzip();
+    let refined = kmeans::refine(&colors, &samples, iterations);
+    #[cfg(feature = "debug-stderr")]
+    eprintln!("K-means: Refined palette in {} ms.", ms(time_refine));
+
+    let mut palette_as_bytes = palette.colors.clone();
+    for (&index, color) in indices.iter().zip(refined.iter()) {
+        let i = index as usize * 3;
+        palette_as_bytes[i..i + 3].copy_from_slice(color);
+    }
+    let refined: Vec<(RGBA, Lab)> = refined.iter().map(|c| {
+        let rgba = [c[0], c[1], c[2], 255];
+        (rgba, Lab::from_rgba(&rgba))
+    }).collect();
+    let index_of = move |px: &RGBA| {
+        match transparency {
+            Some(index) if px[3] == 0 => index,
+            _ => indices[closest_lab(&refined, &Lab::from_rgba(px))],
+        }
+    };
+
+    Palette {
+        colors: palette_as_bytes,
+        transparency,
+        index_of: Box::new(index_of),
+    }
+}
+
+/// How many opaque colors fit in a palette of `max_colors`, leaving a slot
+/// for transparent pixels if there are any.
+fn opaque_colors(max_colors: usize, has_transparency: bool) -> usize {
+    if has_transparency { max_colors - 1 } else { max_colors }
+}
+
+/// Palette bytes for the colors, followed by an entry for transparent pixels
+/// if there are any, along with its index.
+fn palette_bytes(colors: &[Rgb], has_transparency: bool) -> (Vec<u8>, Option<u8>) {
+    let mut bytes = Vec::with_capacity((colors.len() + 1) * 3);
+    for color in colors {
+        bytes.extend_from_slice(color);
+    }
+    if has_transparency {
+        bytes.extend_from_slice(&[0, 0, 0]);
+        (bytes, Some(colors.len() as u8))
+    } else {
+        (bytes, None)
+    }
+}
+
+/// Index of the palette color nearest to `color` in Lab space.
+fn closest_lab(palette: &[(RGBA, Lab)], color: &Lab) -> usize {
+    palette.iter().enumerate().fold((0, f32::INFINITY), |closest, (idx, p)| {
+        let dist = p.1.squared_distance(color);
+        if closest.1 < dist {
+            closest
+        } else {
+            (idx, dist)
+        }
+    }).0
 }
 
 #[cfg(test)]
 #[allow(unused_must_use)]
 mod tests {
-    use super::{load_image, engiffen, Error, Quantizer};
+    use super::{load_image, engiffen, engiffen_with_options, engiffen_with_delays, engiffen_with_quantizer, delays_for_fps, detect_scenes, MAX_DELAY};
+    use std::sync::Arc;
+    use super::{Dither, Disposal, Encoder, Error, FixedPalette, Gif, Image, LocalPalette, Loop, Matte, Options, Palette, PaletteFormat, PaletteMode, Quantize, Quantizer};
+    use std::ffi::OsStr;
     use std::fs::{read_dir, File};
+    use std::path::PathBuf;
+
+    fn sorted_paths(dir: &str, extension: &str) -> Vec<PathBuf> {
+        let mut paths: Vec<_> = read_dir(dir).unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|path| path.extension() == Some(OsStr::new(extension)))
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    fn ball_frames() -> Vec<Image> {
+        sorted_paths("tests/ball", "bmp").iter()
+            .map(|path| load_image(path).unwrap())
+            .collect()
+    }
 
     #[test]
     fn test_error_on_size_mismatch() {
-        let imgs: Vec<_> = read_dir("tests/mismatched_size").unwrap()
-        .map(|e| e.unwrap().path())
-        .map(|path| load_image(&path).unwrap())
+        let imgs: Vec<_> = sorted_paths("tests/mismatched_size", "bmp").iter()
+        .map(|path| load_image(path).unwrap())
         .collect();
 
         let res = engiffen(&imgs, 30, Quantizer::NeuQuant(1));
@@ -433,6 +1744,983 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_encoder_matches_engiffen() {
+        let imgs = ball_frames();
+        let expected = engiffen(&imgs, 10, Quantizer::NeuQuant(4)).unwrap();
+
+        let mut encoder = Encoder::new(10.0, Quantizer::NeuQuant(4)).unwrap();
+        for img in ball_frames() {
+            encoder.push(img).unwrap();
+        }
+        assert_eq!(encoder.len(), imgs.len());
+        assert_eq!(encoder.finish().unwrap(), expected);
+
+        // Frames are unpacked for refinement and palettes of their own too
+        let frames = sprite_frames();
+        let delays = delays_for_fps(10.0, frames.len()).unwrap();
+        for &palette_mode in &[PaletteMode::PerFrame, PaletteMode::PerScene, PaletteMode::Auto] {
+            let options = Options { quantizer: Quantizer::Wu, kmeans: 2, dither: Dither::FloydSteinberg, palette_mode, ..Options::default() };
+            let mut encoder = Encoder::with_options(10.0, options.clone()).unwrap();
+            for img in sprite_frames() {
+                encoder.push(img).unwrap();
+            }
+            assert_eq!(encoder.finish().unwrap(), engiffen_with_delays(&frames, &delays, &options).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_encoder_error_on_size_mismatch() {
+        let mut encoder = Encoder::new(30.0, Quantizer::Naive).unwrap();
+        for path in sorted_paths("tests/mismatched_size", "bmp").iter().take(2) {
+            encoder.push(load_image(path).unwrap()).unwrap();
+        }
+        let res = encoder.push(load_image("tests/mismatched_size/mismatch-03.bmp").unwrap());
+        match res {
+            Err(Error::Mismatch(one, another)) => {
+                assert_eq!((one, another), ((100, 100), (50, 50)));
+            },
+            _ => panic!("Pushing a frame of a different size should have failed."),
+        }
+        assert_eq!(encoder.len(), 2);
+    }
+
+    #[test]
+    fn test_delays() {
+        let imgs = ball_frames();
+        let gif = engiffen(&imgs, 20, Quantizer::Naive).unwrap();
+        assert_eq!(gif.delays, vec![50; imgs.len()]);
+
+        let mut delays = vec![40; imgs.len()];
+        delays[0] = 2000;
+        let gif = engiffen_with_delays(&imgs, &delays, &Options::from(Quantizer::Naive)).unwrap();
+        assert_eq!(gif.delays, delays);
+
+        let mut encoder = Encoder::new(25.0, Quantizer::Naive).unwrap();
+        let mut frames = ball_frames().into_iter();
+        encoder.push_with_delay(frames.next().unwrap(), 2000).unwrap();
+        for img in frames {
+            encoder.push(img).unwrap();
+        }
+        assert_eq!(encoder.finish().unwrap().delays, delays);
+    }
+
+    #[test]
+    fn test_delays_for_fps_do_not_drift() {
+        for &fps in &[24.0, 30.0, 60.0, 15.0, 23.976, 29.97, 30000.0 / 1001.0, 12.5, 7.0] {
+            let delays = delays_for_fps(fps, 10000).unwrap();
+            let mut elapsed = 0.0;
+            for (n, delay) in delays.iter().enumerate() {
+                assert_eq!(delay % 10, 0);
+                elapsed += *delay as f64 / 10.0;
+                let expected = (n + 1) as f64 * 100.0 / fps;
+                assert!((elapsed - expected).abs() <= 1.0,
+                    "{} fps drifted to {} cs instead of {} cs after {} frames", fps, elapsed, expected, n + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_written_delays_are_rounded() {
+        let imgs = ball_frames();
+        let mut delays = vec![40; imgs.len()];
+        delays[0] = MAX_DELAY;
+        delays[1] = 14;
+        delays[2] = 15;
+        let expected: Vec<u16> = delays.iter().map(|&delay| ((delay + 5) / 10) as u16).collect();
+        assert_eq!(&expected[..3], &[65535, 1, 2]);
+        let mut gif = engiffen_with_delays(&imgs, &delays, &Options::from(Quantizer::Naive)).unwrap();
+        for &lossy in &[0, 20] {
+            gif.lossy = lossy;
+            let mut bytes = vec![];
+            gif.write(&mut bytes).unwrap();
+            let written: Vec<u16> = extensions(&bytes).into_iter()
+                .filter(|&(label, _)| label == 0xF9)
+                .map(|(_, data)| data[1] as u16 | (data[2] as u16) << 8)
+                .collect();
+            assert_eq!(written, expected);
+        }
+    }
+
+    #[test]
+    fn test_encoder_delays_for_fps() {
+        let mut encoder = Encoder::new(29.97, Quantizer::Naive).unwrap();
+        for img in ball_frames() {
+            encoder.push(img).unwrap();
+        }
+        let len = encoder.len();
+        assert_eq!(encoder.finish().unwrap().delays, delays_for_fps(29.97, len).unwrap());
+    }
+
+    /// Walks the blocks of an encoded Gif, returning the payload of each
+    /// extension block along with its label.
+    fn extensions(bytes: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        fn skip_sub_blocks(bytes: &[u8], mut pos: usize, data: &mut Vec<u8>) -> usize {
+            while bytes[pos] != 0 {
+                let len = bytes[pos] as usize;
+                data.extend_from_slice(&bytes[pos + 1..pos + 1 + len]);
+                pos += len + 1;
+            }
+            pos + 1
+        }
+        fn color_table_len(flags: u8) -> usize {
+            if flags & 0x80 == 0 { 0 } else { 3 << ((flags & 0x07) + 1) }
+        }
+
+        assert_eq!(&bytes[..6], b"GIF89a");
+        let mut pos = 13 + color_table_len(bytes[10]);
+        let mut found = vec![];
+        loop {
+            match bytes[pos] {
+                0x21 => {
+                    let label = bytes[pos + 1];
+                    let mut data = vec![];
+                    pos = skip_sub_blocks(bytes, pos + 2, &mut data);
+                    found.push((label, data));
+                },
+                0x2C => {
+                    pos += 10 + color_table_len(bytes[pos + 9]) + 1;
+                    pos = skip_sub_blocks(bytes, pos, &mut vec![]);
+                },
+                0x3B => return found,
+                b => panic!("Unexpected block {:x} at {}", b, pos),
+            }
+        }
+    }
+
+    fn netscape_loops(gif: &Gif) -> Option<u16> {
+        let mut bytes = vec![];
+        gif.write(&mut bytes).unwrap();
+        let mut reader = gif::Decoder::new(&bytes[..]).read_info().unwrap();
+        let mut frames = 0;
+        while reader.read_next_frame().unwrap().is_some() {
+            frames += 1;
+        }
+        assert_eq!(frames, gif.images.len());
+
+        let netscape: Vec<_> = extensions(&bytes).into_iter()
+            .filter(|&(label, ref data)| label == 0xFF && data.starts_with(b"NETSCAPE2.0"))
+            .collect();
+        assert!(netscape.len() <= 1);
+        netscape.first().map(|(_, data)| data[12] as u16 | (data[13] as u16) << 8)
+    }
+
+    #[test]
+    fn test_looping() {
+        let imgs = ball_frames();
+        let mut gif = engiffen(&imgs[..2], 10, Quantizer::NeuQuant(10)).unwrap();
+        assert_eq!(gif.looping, Loop::Infinite);
+        assert_eq!(netscape_loops(&gif), Some(0));
+
+        gif.looping = Loop::Repeat(3);
+        assert_eq!(netscape_loops(&gif), Some(3));
+
+        gif.looping = Loop::Once;
+        assert_eq!(netscape_loops(&gif), None);
+
+        gif.looping = Loop::Repeat(0);
+        assert_eq!(netscape_loops(&gif), None);
+    }
+
+    /// Decodes a Gif the way a viewer would display it, returning the canvas
+    /// after each frame, with `None` for see-through pixels.
+    fn composite(bytes: &[u8]) -> Vec<Vec<Option<[u8; 3]>>> {
+        use gif::DisposalMethod;
+
+        let mut reader = gif::Decoder::new(bytes).read_info().unwrap();
+        let width = reader.width() as usize;
+        let global_palette = reader.global_palette().map(|p| p.to_vec());
+        let mut canvas = vec![None; width * reader.height() as usize];
+        let mut canvases = vec![];
+        while let Some(frame) = reader.read_next_frame().unwrap() {
+            let palette = frame.palette.clone().or_else(|| global_palette.clone()).unwrap();
+            let previous = canvas.clone();
+            let (left, top) = (frame.left as usize, frame.top as usize);
+            let frame_width = frame.width as usize;
+            for (n, &idx) in frame.buffer.iter().enumerate() {
+                if Some(idx) != frame.transparent {
+                    let i = idx as usize * 3;
+                    let p = (top + n / frame_width) * width + left + n % frame_width;
+                    canvas[p] = Some([palette[i], palette[i + 1], palette[i + 2]]);
+                }
+            }
+            canvases.push(canvas.clone());
+            match frame.dispose {
+                DisposalMethod::Background => {
+                    for n in 0..frame.buffer.len() {
+                        canvas[(top + n / frame_width) * width + left + n % frame_width] = None;
+                    }
+                },
+                DisposalMethod::Previous => canvas = previous,
+                _ => {},
+            }
+        }
+        canvases
+    }
+
+    /// The colors each of a Gif's frames should display.
+    fn expected_frames(gif: &Gif) -> Vec<Vec<Option<[u8; 3]>>> {
+        gif.images.iter().enumerate().map(|(n, img)| {
+            let (palette, transparency) = gif.frame_palette(n);
+            img.iter().map(|&idx| {
+                if Some(idx) == transparency {
+                    None
+                } else {
+                    let i = idx as usize * 3;
+                    Some([palette[i], palette[i + 1], palette[i + 2]])
+                }
+            }).collect()
+        }).collect()
+    }
+
+    #[test]
+    fn test_optimize_shrinks_ball() {
+        let imgs = ball_frames();
+        let mut gif = engiffen(&imgs, 10, Quantizer::NeuQuant(2)).unwrap();
+        let mut full = vec![];
+        gif.write(&mut full).unwrap();
+
+        gif.optimize = true;
+        let mut optimized = vec![];
+        gif.write(&mut optimized).unwrap();
+
+        assert_eq!(composite(&optimized), expected_frames(&gif));
+        assert_eq!(composite(&full), expected_frames(&gif));
+        let reference = ::std::fs::metadata("tests/ball.gif").unwrap().len() as usize;
+        assert!(optimized.len() < full.len() / 2, "optimized to {} bytes from {}", optimized.len(), full.len());
+        assert!(optimized.len() < reference / 2, "optimized to {} bytes, tests/ball.gif is {}", optimized.len(), reference);
+    }
+
+    // A 2x2 red sprite moving across a transparent 8x4 canvas
+    fn sprite_frames() -> Vec<Image> {
+        (0..4).map(|n| {
+            let mut pixels = vec![[0, 0, 0, 0]; 8 * 4];
+            for &(x, y) in &[(n * 2, 1), (n * 2 + 1, 1), (n * 2, 2), (n * 2 + 1, 2)] {
+                pixels[y * 8 + x] = [255, 0, 0, 255];
+            }
+            Image { pixels, width: 8, height: 4 }
+        }).collect()
+    }
+
+    // Source frames as the decoded composite should show them, allowing for
+    // a little quantization error in the opaque colors.
+    fn matches_source(canvases: &[Vec<Option<[u8; 3]>>], frames: &[Image]) -> bool {
+        canvases.len() == frames.len() && canvases.iter().zip(frames.iter()).all(|(canvas, img)| {
+            canvas.iter().zip(img.pixels.iter()).all(|(color, px)| {
+                match *color {
+                    None => px[3] == 0,
+                    Some(rgb) => px[3] != 0 && (0..3).all(|i| (rgb[i] as i32 - px[i] as i32).abs() <= 8),
+                }
+            })
+        })
+    }
+
+    /// Opaque black and gray shapes moving over a background that's fully
+    /// transparent, with junk in its color channels, and two pixels that are
+    /// partly transparent.
+    fn alpha_frames() -> Vec<Image> {
+        (0..3).map(|n| {
+            let mut pixels = vec![[255, 255, 255, 0]; 8 * 4];
+            for x in 0..4 {
+                pixels[8 + (x + n) % 8] = [0, 0, 0, 255];
+                pixels[16 + (x + n) % 8] = [128, 128, 128, 255];
+            }
+            pixels[24] = [0, 0, 255, 100];
+            pixels[25] = [0, 0, 255, 200];
+            Image { pixels, width: 8, height: 4 }
+        }).collect()
+    }
+
+    #[test]
+    fn test_transparency_in_every_quantizer() {
+        let frames = alpha_frames();
+        let delays = delays_for_fps(10.0, frames.len()).unwrap();
+        for &quantizer in &[Quantizer::NeuQuant(1), Quantizer::Naive, Quantizer::MedianCut, Quantizer::Octree, Quantizer::Wu] {
+            for &colors in &[256, 4, 2] {
+                let options = Options { quantizer, colors, ..Options::default() };
+                let gif = engiffen_with_delays(&frames, &delays, &options).unwrap();
+                let transparency = gif.transparency.expect("Transparent pixels should get a palette entry.");
+                assert_eq!(transparency as usize, gif.palette.len() / 3 - 1, "{:?}", quantizer);
+                assert!(gif.palette.len() <= colors as usize * 3);
+                for (img, frame) in gif.images.iter().zip(frames.iter()) {
+                    for (&idx, px) in img.iter().zip(frame.pixels.iter()) {
+                        assert_eq!(idx == transparency, px[3] == 0, "{:?} with {} colors", quantizer, colors);
+                    }
+                }
+
+                let mut bytes = vec![];
+                gif.write(&mut bytes).unwrap();
+                assert!(composite(&bytes) == expected_frames(&gif));
+            }
+
+            let blank = vec![Image { pixels: vec![[0, 0, 0, 0]; 4], width: 2, height: 2 }];
+            let gif = engiffen(&blank, 10, quantizer).unwrap();
+            assert!(gif.transparency.is_some());
+            assert!(gif.images[0].iter().all(|&idx| Some(idx) == gif.transparency));
+        }
+    }
+
+    #[test]
+    fn test_antialiased_edges_have_no_duplicate_colors() {
+        // A red square on a transparent background, with edges that fade out
+        let pixels = (0..8 * 8).map(|n| {
+            let (x, y) = (n % 8, n / 8);
+            match (x.min(7 - x)).min(y.min(7 - y)) {
+                0 => [0, 0, 0, 0],
+                1 => [255, 0, 0, 64],
+                2 => [255, 0, 0, 160],
+                _ => [255, 0, 0, 255],
+            }
+        }).collect();
+        let frames = vec![Image { pixels, width: 8, height: 8 }];
+        for &quantizer in &[Quantizer::Naive, Quantizer::MedianCut, Quantizer::Octree, Quantizer::Wu] {
+            let gif = engiffen_with_delays(&frames, &[100], &Options::from(quantizer)).unwrap();
+            let transparency = gif.transparency.expect("Transparent pixels should get a palette entry.");
+            let opaque: Vec<&[u8]> = gif.palette.chunks(3)
+                .enumerate()
+                .filter(|&(n, _)| n != transparency as usize)
+                .map(|(_, color)| color)
+                .collect();
+            assert_eq!(opaque, vec![&[255, 0, 0][..]], "{:?}", quantizer);
+        }
+    }
+
+    #[test]
+    fn test_alpha_threshold() {
+        let frames = alpha_frames();
+        let delays = delays_for_fps(10.0, frames.len()).unwrap();
+        let transparent_pixels = |threshold| {
+            let options = Options { quantizer: Quantizer::MedianCut, alpha_threshold: threshold, ..Options::default() };
+            let gif = engiffen_with_delays(&frames, &delays, &options).unwrap();
+            let mut encoder = Encoder::with_options(10.0, options).unwrap();
+            for frame in alpha_frames() {
+                encoder.push(frame).unwrap();
+            }
+            assert_eq!(encoder.finish().unwrap().images, gif.images);
+            gif.images[0].iter().filter(|&&idx| Some(idx) == gif.transparency).count()
+        };
+        assert_eq!(transparent_pixels(1), 8 * 4 - 10);
+        assert_eq!(transparent_pixels(128), 8 * 4 - 9);
+        assert_eq!(transparent_pixels(255), 8 * 4 - 8);
+        assert_eq!(transparent_pixels(0), 0);
+    }
+
+    #[test]
+    fn test_matte() {
+        let frames = vec![Image { pixels: vec![[255, 0, 0, 128], [0, 0, 255, 255], [0, 255, 0, 0], [0, 0, 0, 64]], width: 2, height: 2 }];
+        let colors = |options: &Options| {
+            let gif = engiffen_with_delays(&frames, &[100], options).unwrap();
+            expected_frames(&gif).remove(0)
+        };
+        let options = Options { quantizer: Quantizer::Naive, ..Options::default() };
+        assert_eq!(colors(&options), vec![Some([255, 0, 0]), Some([0, 0, 255]), None, Some([0, 0, 0])]);
+
+        let white = Options { matte: Some(Matte::Color([255, 255, 255])), ..options.clone() };
+        assert_eq!(colors(&white), vec![Some([255, 127, 127]), Some([0, 0, 255]), None, Some([191, 191, 191])]);
+
+        // A matte image is tiled under the frame
+        let checker = Image { pixels: vec![[0, 0, 0, 255], [255, 255, 255, 255]], width: 1, height: 2 };
+        let checkered = Options { matte: Some(Matte::Image(Arc::new(checker))), alpha_threshold: 0, ..options.clone() };
+        assert_eq!(colors(&checkered), vec![Some([128, 0, 0]), Some([0, 0, 255]), Some([255, 255, 255]), Some([191, 191, 191])]);
+
+        let mut encoder = Encoder::with_options(10.0, white.clone()).unwrap();
+        encoder.push_with_delay(Image { pixels: frames[0].pixels.clone(), width: 2, height: 2 }, 100).unwrap();
+        assert_eq!(encoder.finish().unwrap(), engiffen_with_delays(&frames, &[100], &white).unwrap());
+    }
+
+    #[test]
+    fn test_auto_disposal_clears_trails() {
+        let frames = sprite_frames();
+        let gif = engiffen(&frames, 10, Quantizer::NeuQuant(1)).unwrap();
+        assert_eq!(gif.disposals, vec![Disposal::Auto; 4]);
+        let mut bytes = vec![];
+        gif.write(&mut bytes).unwrap();
+        assert!(matches_source(&composite(&bytes), &frames));
+
+        // Only the last frame has nothing after it to clear for
+        let mut reader = gif::Decoder::new(&bytes[..]).read_info().unwrap();
+        let mut disposals = vec![];
+        while let Some(frame) = reader.read_next_frame().unwrap() {
+            disposals.push(frame.dispose);
+        }
+        assert_eq!(disposals, vec![
+            gif::DisposalMethod::Background,
+            gif::DisposalMethod::Background,
+            gif::DisposalMethod::Background,
+            gif::DisposalMethod::Keep,
+        ]);
+    }
+
+    #[test]
+    fn test_explicit_disposals() {
+        let frames = sprite_frames();
+        let mut gif = engiffen(&frames, 10, Quantizer::NeuQuant(1)).unwrap();
+        gif.disposals = vec![Disposal::Keep; 4];
+        let mut bytes = vec![];
+        gif.write(&mut bytes).unwrap();
+        let canvases = composite(&bytes);
+        assert!(!matches_source(&canvases, &frames));
+        // Every sprite drawn so far stays on the canvas
+        assert!(canvases[3].iter().filter(|c| c.is_some()).count() == 16);
+
+        gif.disposals = vec![Disposal::Previous; 4];
+        let mut bytes = vec![];
+        gif.write(&mut bytes).unwrap();
+        assert!(matches_source(&composite(&bytes), &frames));
+        let mut reader = gif::Decoder::new(&bytes[..]).read_info().unwrap();
+        while let Some(frame) = reader.read_next_frame().unwrap() {
+            assert_eq!(frame.dispose, gif::DisposalMethod::Previous);
+        }
+
+        for &optimize in &[false, true] {
+            gif.optimize = optimize;
+            gif.disposals = vec![Disposal::Keep, Disposal::Background, Disposal::Previous, Disposal::Auto];
+            let mut bytes = vec![];
+            gif.write(&mut bytes).unwrap();
+            let canvases = composite(&bytes);
+            // The first sprite is kept under the second, then cleared along
+            // with it, and the third is removed again before the fourth.
+            assert_eq!(canvases[1].iter().filter(|c| c.is_some()).count(), 8);
+            assert!(matches_source(&canvases[2..], &frames[2..]));
+            assert!(matches_source(&canvases[3..], &frames[3..]));
+        }
+    }
+
+    #[test]
+    fn test_optimize_clears_transparent_pixels() {
+        let frames = sprite_frames();
+        let mut gif = engiffen(&frames, 10, Quantizer::NeuQuant(1)).unwrap();
+        assert!(gif.transparency.is_some());
+        gif.optimize = true;
+        let mut bytes = vec![];
+        gif.write(&mut bytes).unwrap();
+        assert_eq!(composite(&bytes), expected_frames(&gif));
+    }
+
+    #[test]
+    fn test_median_cut() {
+        let imgs = ball_frames();
+        let gif = engiffen(&imgs, 10, Quantizer::MedianCut).unwrap();
+        assert!(gif.palette.len() <= 256 * 3);
+        assert_eq!(gif.transparency, None);
+
+        let mut encoder = Encoder::new(10.0, Quantizer::MedianCut).unwrap();
+        for img in imgs {
+            encoder.push(img).unwrap();
+        }
+        assert_eq!(encoder.finish().unwrap(), gif);
+    }
+
+    #[test]
+    fn test_median_cut_transparency() {
+        let frames = sprite_frames();
+        let gif = engiffen(&frames, 10, Quantizer::MedianCut).unwrap();
+        assert_eq!(gif.palette, vec![255, 0, 0, 0, 0, 0]);
+        assert_eq!(gif.transparency, Some(1));
+        let mut bytes = vec![];
+        gif.write(&mut bytes).unwrap();
+        assert!(matches_source(&composite(&bytes), &frames));
+    }
+
+    #[test]
+    fn test_octree() {
+        let imgs = ball_frames();
+        let gif = engiffen(&imgs, 10, Quantizer::Octree).unwrap();
+        assert!(gif.palette.len() <= 256 * 3);
+        assert_eq!(gif.transparency, None);
+
+        let frames = sprite_frames();
+        let gif = engiffen(&frames, 10, Quantizer::Octree).unwrap();
+        assert_eq!(gif.palette, vec![255, 0, 0, 0, 0, 0]);
+        assert_eq!(gif.transparency, Some(1));
+        let mut bytes = vec![];
+        gif.write(&mut bytes).unwrap();
+        assert!(matches_source(&composite(&bytes), &frames));
+    }
+
+    // Mean squared distance between the source pixels and the palette colors
+    // they were mapped to
+    fn mean_error(imgs: &[Image], gif: &Gif) -> f64 {
+        let mut total = 0.0;
+        let mut count = 0;
+        for (img, indices) in imgs.iter().zip(gif.images.iter()) {
+            for (px, &idx) in img.pixels.iter().zip(indices.iter()) {
+                let color = &gif.palette[idx as usize * 3..idx as usize * 3 + 3];
+                total += (0..3).map(|c| (px[c] as f64 - color[c] as f64).powi(2)).sum::<f64>();
+                count += 1;
+            }
+        }
+        total / count as f64
+    }
+
+    // Mean squared distance in Lab space between the source pixels and the
+    // palette colors they were mapped to
+    fn mean_lab_error(imgs: &[Image], gif: &Gif) -> f64 {
+        use lab::Lab;
+        let palette: Vec<Lab> = gif.palette.chunks(3).map(|c| Lab::from_rgb(&[c[0], c[1], c[2]])).collect();
+        let mut total = 0.0;
+        let mut count = 0;
+        for (img, indices) in imgs.iter().zip(gif.images.iter()) {
+            for (px, &idx) in img.pixels.iter().zip(indices.iter()) {
+                total += palette[idx as usize].squared_distance(&Lab::from_rgba(px)) as f64;
+                count += 1;
+            }
+        }
+        total / count as f64
+    }
+
+    #[test]
+    fn test_wu() {
+        let imgs = ball_frames();
+        let gif = engiffen(&imgs, 10, Quantizer::Wu).unwrap();
+        assert!(gif.palette.len() <= 256 * 3);
+        assert_eq!(gif.transparency, None);
+        // On the ball frames, Wu beats every other quantizer
+        let wu_error = mean_error(&imgs, &gif);
+        for &quantizer in &[Quantizer::Naive, Quantizer::NeuQuant(4), Quantizer::MedianCut, Quantizer::Octree] {
+            let other = engiffen(&imgs, 10, quantizer).unwrap();
+            let error = mean_error(&imgs, &other);
+            assert!(wu_error < error, "Wu error {} isn't lower than {:?} error {}", wu_error, quantizer, error);
+        }
+
+        let frames = sprite_frames();
+        let gif = engiffen(&frames, 10, Quantizer::Wu).unwrap();
+        assert_eq!(gif.palette, vec![255, 0, 0, 0, 0, 0]);
+        assert_eq!(gif.transparency, Some(1));
+        let mut bytes = vec![];
+        gif.write(&mut bytes).unwrap();
+        assert!(matches_source(&composite(&bytes), &frames));
+    }
+
+    #[test]
+    fn test_kmeans_refinement() {
+        let imgs = ball_frames();
+        let gif = engiffen(&imgs, 10, Quantizer::MedianCut).unwrap();
+        let options = Options { quantizer: Quantizer::MedianCut, kmeans: 4, ..Options::default() };
+        let refined = engiffen_with_options(&imgs, 10, &options).unwrap();
+        assert_eq!(refined.palette.len(), gif.palette.len());
+        assert!(mean_lab_error(&imgs, &refined) < mean_lab_error(&imgs, &gif) / 2.0);
+
+        let frames = sprite_frames();
+        let options = Options { quantizer: Quantizer::Wu, kmeans: 4, ..Options::default() };
+        let gif = engiffen_with_options(&frames, 10, &options).unwrap();
+        assert_eq!(gif.transparency, Some(1));
+        let mut bytes = vec![];
+        gif.write(&mut bytes).unwrap();
+        assert!(matches_source(&composite(&bytes), &frames));
+    }
+
+    struct Threshold;
+
+    impl Quantize for Threshold {
+        fn palette(&self, _imgs: &[Image], _max_colors: usize) -> Palette {
+            Palette {
+                colors: vec![0, 0, 0, 255, 255, 255, 0, 0, 0],
+                transparency: Some(2),
+                index_of: Box::new(|px| {
+                    if px[3] == 0 {
+                        2
+                    } else if px[0] as u32 + px[1] as u32 + px[2] as u32 >= 384 {
+                        1
+                    } else {
+                        0
+                    }
+                }),
+            }
+        }
+    }
+
+    #[test]
+    fn test_custom_quantizer() {
+        let imgs = ball_frames();
+        let delays = delays_for_fps(10.0, imgs.len()).unwrap();
+        let gif = engiffen_with_quantizer(&imgs, &delays, &Threshold, &Options::default()).unwrap();
+        assert_eq!(gif.palette, vec![0, 0, 0, 255, 255, 255, 0, 0, 0]);
+        assert_eq!(gif.transparency, Some(2));
+        assert!(gif.images.iter().all(|img| img.iter().all(|&idx| idx < 2)));
+        assert!(gif.images.iter().any(|img| img.contains(&0)));
+        assert!(gif.images.iter().any(|img| img.contains(&1)));
+
+        // Options other than the quantizer still apply
+        let options = Options { dither: Dither::Bayer4, ..Options::default() };
+        let dithered = engiffen_with_quantizer(&imgs, &delays, &Threshold, &options).unwrap();
+        assert!(dithered.images != gif.images);
+
+        // Refinement leaves the transparent entry alone
+        let options = Options { kmeans: 2, ..Options::default() };
+        let frames = sprite_frames();
+        let refined = engiffen_with_quantizer(&frames, &delays[..4], &Threshold, &options).unwrap();
+        assert_eq!(&refined.palette[6..], &[0, 0, 0]);
+        assert_eq!(refined.transparency, Some(2));
+        let mut bytes = vec![];
+        refined.write(&mut bytes).unwrap();
+        assert!(matches_source(&composite(&bytes), &frames));
+    }
+
+    /// Quantizer that returns a gray palette of `colors` colors, and maps
+    /// every pixel to `index`.
+    struct Misbehaving {
+        colors: usize,
+        transparency: Option<u8>,
+        index: u8,
+    }
+
+    impl Quantize for Misbehaving {
+        fn palette(&self, _imgs: &[Image], _max_colors: usize) -> Palette {
+            let index = self.index;
+            Palette {
+                colors: (0..self.colors).flat_map(|n| vec![n as u8; 3]).collect(),
+                transparency: self.transparency,
+                index_of: Box::new(move |_| index),
+            }
+        }
+    }
+
+    #[test]
+    fn test_error_on_invalid_quantizer_palette() {
+        let imgs = ball_frames();
+        let delays = delays_for_fps(10.0, imgs.len()).unwrap();
+        let options = Options { colors: 16, ..Options::default() };
+        let engiffen = |quantizer: &Misbehaving, options: &Options| engiffen_with_quantizer(&imgs, &delays, quantizer, options);
+
+        assert!(engiffen(&Misbehaving { colors: 16, transparency: Some(15), index: 3 }, &options).is_ok());
+        let invalid = [
+            Misbehaving { colors: 0, transparency: None, index: 0 },
+            Misbehaving { colors: 17, transparency: None, index: 0 },
+            Misbehaving { colors: 300, transparency: None, index: 0 },
+            Misbehaving { colors: 4, transparency: Some(4), index: 0 },
+            Misbehaving { colors: 4, transparency: None, index: 4 },
+        ];
+        for quantizer in &invalid {
+            for &(dither, palette_mode) in &[(Dither::None, PaletteMode::Global), (Dither::FloydSteinberg, PaletteMode::Global), (Dither::Bayer4, PaletteMode::PerFrame)] {
+                let options = Options { dither, palette_mode, ..options.clone() };
+                match engiffen(quantizer, &options) {
+                    Err(Error::InvalidPalette(_)) => {},
+                    other => panic!("Expected Error::InvalidPalette for {} colors, got {:?}", quantizer.colors, other),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_palette_size() {
+        let imgs = ball_frames();
+        let delays = delays_for_fps(10.0, imgs.len()).unwrap();
+        for &quantizer in &[Quantizer::NeuQuant(4), Quantizer::Naive, Quantizer::MedianCut, Quantizer::Octree, Quantizer::Wu] {
+            let options = Options { quantizer, colors: 16, ..Options::default() };
+            let gif = engiffen_with_delays(&imgs, &delays, &options).unwrap();
+            assert!(gif.palette.len() <= 16 * 3, "{:?} made {} colors", quantizer, gif.palette.len() / 3);
+            assert!(gif.images.iter().all(|img| img.iter().all(|&idx| (idx as usize) < gif.palette.len() / 3)));
+
+            // The color table in the file is only as big as it needs to be
+            let mut bytes = vec![];
+            gif.write(&mut bytes).unwrap();
+            let table_size = 2 << (bytes[10] & 7);
+            assert!(table_size <= 16 && table_size >= gif.palette.len() / 3);
+        }
+
+        // The transparent color counts toward the size
+        let frames = sprite_frames();
+        let options = Options { quantizer: Quantizer::MedianCut, colors: 2, ..Options::default() };
+        let gif = engiffen_with_delays(&frames, &delays[..4], &options).unwrap();
+        assert_eq!(gif.palette, vec![255, 0, 0, 0, 0, 0]);
+        assert_eq!(gif.transparency, Some(1));
+
+        // Out of range sizes are clamped
+        let options = Options { quantizer: Quantizer::NeuQuant(4), colors: 0, ..Options::default() };
+        let gif = engiffen_with_delays(&imgs, &delays, &options).unwrap();
+        assert_eq!(gif.palette.len(), 2 * 3);
+    }
+
+    #[test]
+    fn test_palette_size_shrinks_file() {
+        let imgs = ball_frames();
+        let delays = delays_for_fps(10.0, imgs.len()).unwrap();
+        let write = |colors| {
+            let options = Options { quantizer: Quantizer::Wu, colors, ..Options::default() };
+            let mut bytes = vec![];
+            engiffen_with_delays(&imgs, &delays, &options).unwrap().write(&mut bytes).unwrap();
+            bytes.len()
+        };
+        assert!(write(4) < write(256));
+    }
+
+    /// Three frames of red gradients followed by three of blue ones.
+    fn scene_cut_frames() -> Vec<Image> {
+        (0..6u8).map(|n| {
+            let pixels = (0..256u32).map(|p| {
+                let shade = (p as u8).saturating_add(n);
+                if n < 3 { [shade, 0, 0, 255] } else { [0, 0, shade, 255] }
+            }).collect();
+            Image { pixels, width: 16, height: 16 }
+        }).collect()
+    }
+
+    #[test]
+    fn test_local_palettes() {
+        let imgs = scene_cut_frames();
+        let delays = delays_for_fps(10.0, imgs.len()).unwrap();
+        let options = |mode| Options { quantizer: Quantizer::MedianCut, colors: 8, palette_mode: mode, ..Options::default() };
+        let error = |gif: &Gif| -> f64 {
+            let frames = expected_frames(gif);
+            imgs.iter().zip(frames.iter()).map(|(img, frame)| {
+                img.pixels.iter().zip(frame.iter()).map(|(px, color)| {
+                    let color = color.unwrap();
+                    (0..3).map(|c| (px[c] as f64 - color[c] as f64).powi(2)).sum::<f64>()
+                }).sum::<f64>()
+            }).sum()
+        };
+
+        let global = engiffen_with_delays(&imgs, &delays, &options(PaletteMode::Global)).unwrap();
+        assert!(global.local_palettes.iter().all(Option::is_none));
+
+        let local = engiffen_with_delays(&imgs, &delays, &options(PaletteMode::PerFrame)).unwrap();
+        assert_eq!(local.local_palettes.len(), imgs.len());
+        assert!(local.local_palettes.iter().all(|p| p.as_ref().is_some_and(|p| p.colors.len() <= 8 * 3)));
+        assert!(error(&local) * 2.0 < error(&global));
+
+        // Frames are written with their own color tables
+        for &optimize in &[false, true] {
+            let mut gif = local.clone();
+            gif.optimize = optimize;
+            let mut bytes = vec![];
+            gif.write(&mut bytes).unwrap();
+            assert_eq!(composite(&bytes), expected_frames(&gif));
+        }
+
+        // Splitting the palette between the scenes is bad enough for every
+        // frame to get its own
+        let auto = engiffen_with_delays(&imgs, &delays, &options(PaletteMode::Auto)).unwrap();
+        assert_eq!(auto, local);
+
+        // but frames from the same scene share the global palette
+        let auto = engiffen_with_delays(&imgs[..3], &delays[..3], &options(PaletteMode::Auto)).unwrap();
+        assert!(auto.local_palettes.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn test_scene_palettes() {
+        let imgs = scene_cut_frames();
+        assert_eq!(detect_scenes(&imgs), vec![0..3, 3..6]);
+        assert_eq!(detect_scenes(&ball_frames()).len(), 1);
+
+        let options = Options { quantizer: Quantizer::MedianCut, colors: 8, palette_mode: PaletteMode::PerScene, ..Options::default() };
+        let gif = engiffen_with_options(&imgs, 10, &options).unwrap();
+        let palettes: Vec<&LocalPalette> = gif.local_palettes.iter().map(|p| p.as_ref().unwrap()).collect();
+        assert!(palettes[0] == palettes[1] && palettes[1] == palettes[2]);
+        assert!(palettes[3] == palettes[4] && palettes[4] == palettes[5]);
+        assert!(palettes[0] != palettes[3]);
+        assert!(palettes[0].colors.chunks(3).all(|c| c[2] == 0));
+        assert!(palettes[3].colors.chunks(3).all(|c| c[0] == 0));
+
+        let mut bytes = vec![];
+        gif.write(&mut bytes).unwrap();
+        assert_eq!(composite(&bytes), expected_frames(&gif));
+
+        // A single scene keeps the global palette
+        let gif = engiffen_with_options(&imgs[..3], 10, &options).unwrap();
+        assert!(gif.local_palettes.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn test_lossy() {
+        let imgs = ball_frames();
+        let delays = delays_for_fps(10.0, imgs.len()).unwrap();
+        let options = Options { dither: Dither::FloydSteinberg, ..Options::default() };
+        let gif = engiffen_with_delays(&imgs, &delays, &options).unwrap();
+        let distance = |a: [u8; 3], b: [u8; 3]| -> u32 {
+            (0..3).map(|c| (a[c] as i32 - b[c] as i32).pow(2) as u32).sum()
+        };
+
+        for &optimize in &[false, true] {
+            let mut gif = gif.clone();
+            gif.optimize = optimize;
+            let mut lossless = vec![];
+            gif.write(&mut lossless).unwrap();
+
+            gif.lossy = 20;
+            let mut lossy = vec![];
+            gif.write(&mut lossy).unwrap();
+            assert!(lossy.len() < lossless.len(), "{} vs {}", lossy.len(), lossless.len());
+
+            let expected = expected_frames(&gif);
+            let canvases = composite(&lossy);
+            assert_eq!(canvases.len(), expected.len());
+            for (canvas, frame) in canvases.iter().zip(expected.iter()) {
+                assert_eq!(canvas.len(), frame.len());
+                assert!(canvas.iter().zip(frame.iter()).all(|(a, b)| distance(a.unwrap(), b.unwrap()) <= 20 * 20));
+            }
+        }
+
+        // Transparency and local palettes come through untouched
+        let imgs = sprite_frames();
+        let options = Options { palette_mode: PaletteMode::PerFrame, ..Options::default() };
+        let mut gif = engiffen_with_options(&imgs, 10, &options).unwrap();
+        gif.lossy = 80;
+        let mut bytes = vec![];
+        gif.write(&mut bytes).unwrap();
+        let canvases = composite(&bytes);
+        let expected = expected_frames(&gif);
+        assert_eq!(canvases.len(), expected.len());
+        for (canvas, frame) in canvases.iter().zip(expected.iter()) {
+            assert!(canvas.iter().zip(frame.iter()).all(|(a, b)| a.is_none() == b.is_none()));
+        }
+    }
+
+    #[test]
+    fn test_dedupe() {
+        let sprites = sprite_frames();
+        let order = [0, 0, 1, 1, 1, 0, 2];
+        let imgs: Vec<Image> = order.iter().map(|&n| sprites[n].clone()).collect();
+        let delays = [10, 20, 30, 40, 50, 60, 70];
+        let options = Options { quantizer: Quantizer::Naive, dedupe: true, ..Options::default() };
+
+        let gif = engiffen_with_delays(&imgs, &delays, &options).unwrap();
+        assert_eq!(gif.images.len(), 4);
+        assert_eq!(gif.delays, vec![30, 120, 60, 70]);
+        assert_eq!((gif.disposals.len(), gif.local_palettes.len()), (4, 4));
+        let mut bytes = vec![];
+        gif.write(&mut bytes).unwrap();
+        assert!(matches_source(&composite(&bytes), &[sprites[0].clone(), sprites[1].clone(), sprites[0].clone(), sprites[2].clone()]));
+
+        let mut encoder = Encoder::with_options(10.0, options.clone()).unwrap();
+        for (img, &delay) in imgs.iter().zip(delays.iter()) {
+            encoder.push_with_delay(img.clone(), delay).unwrap();
+        }
+        assert_eq!(encoder.finish().unwrap(), gif);
+
+        // Identical frames with palettes of their own get the same palette
+        let options = Options { palette_mode: PaletteMode::PerFrame, ..options };
+        let gif = engiffen_with_delays(&imgs[..3], &delays[..3], &options).unwrap();
+        assert_eq!(gif.delays, vec![30, 30]);
+
+        let gif = engiffen_with_delays(&imgs, &delays, &Options::default()).unwrap();
+        assert_eq!(gif.images.len(), imgs.len());
+
+        // Runs too long for one frame are split instead of cut short
+        let delays = [10, MAX_DELAY, MAX_DELAY - 100, 200, 50, 60, 70];
+        let options = Options { palette_mode: PaletteMode::Global, ..options };
+        let gif = engiffen_with_delays(&imgs, &delays, &options).unwrap();
+        assert_eq!(gif.delays, vec![MAX_DELAY, 10, MAX_DELAY, 150, 60, 70]);
+        assert_eq!(gif.images[0], gif.images[1]);
+        assert_eq!(gif.images[2], gif.images[3]);
+    }
+
+    #[test]
+    fn test_save_palette() {
+        let frames = sprite_frames();
+        let delays = delays_for_fps(10.0, frames.len()).unwrap();
+        let gif = engiffen_with_quantizer(&frames, &delays, &Quantizer::MedianCut, &Options::default()).unwrap();
+        assert!(gif.transparency.is_some());
+
+        // An Adobe color table keeps the transparent entry, so reusing it
+        // gives back the same palette
+        let mut act = vec![];
+        gif.write_palette(&mut act, PaletteFormat::Act).unwrap();
+        let palette = FixedPalette::from_act(&act).unwrap();
+        assert_eq!(palette, FixedPalette::from(&gif));
+        let reused = engiffen_with_quantizer(&frames, &delays, &palette, &Options::default()).unwrap();
+        assert_eq!(reused.palette, gif.palette);
+        assert_eq!(reused.transparency, gif.transparency);
+
+        let mut hex = vec![];
+        gif.write_palette(&mut hex, PaletteFormat::Hex).unwrap();
+        assert_eq!(String::from_utf8(hex).unwrap(), "#FF0000\n");
+    }
+
+    #[test]
+    fn test_fixed_palette_ignores_kmeans() {
+        let imgs = ball_frames();
+        let delays = delays_for_fps(10.0, imgs.len()).unwrap();
+        let palette = FixedPalette::new(vec![[10, 200, 30], [250, 240, 5], [90, 20, 160], [0, 0, 0]]).unwrap();
+        let colors: Vec<u8> = palette.colors().iter().flat_map(|c| c.iter().cloned()).collect();
+        let options = Options { kmeans: 4, palette_mode: PaletteMode::PerFrame, ..Options::default() };
+        let gif = engiffen_with_quantizer(&imgs, &delays, &palette, &options).unwrap();
+        assert_eq!(gif.palette, colors);
+        for local in &gif.local_palettes {
+            assert_eq!(local.as_ref().unwrap().colors, colors);
+        }
+    }
+
+    #[test]
+    fn test_builtin_quantizer_as_trait() {
+        let imgs = ball_frames();
+        let delays = delays_for_fps(10.0, imgs.len()).unwrap();
+        let expected = engiffen(&imgs, 10, Quantizer::MedianCut).unwrap();
+        let quantizer: &dyn Quantize = &Quantizer::MedianCut;
+        let gif = engiffen_with_quantizer(&imgs, &delays, quantizer, &Options::default()).unwrap();
+        assert_eq!(gif, expected);
+    }
+
+    #[test]
+    fn test_error_on_delay_count() {
+        let imgs = ball_frames();
+        let res = engiffen_with_delays(&imgs, &[100, 100], &Options::default());
+        match res {
+            Err(Error::DelayCount(frames, delays)) => assert_eq!((frames, delays), (imgs.len(), 2)),
+            _ => panic!("Mismatched delays should have failed."),
+        }
+    }
+
+    #[test]
+    fn test_error_on_delay_range() {
+        let imgs = ball_frames();
+        let mut delays = vec![40; imgs.len()];
+        delays[1] = MAX_DELAY + 1;
+        match engiffen_with_delays(&imgs, &delays, &Options::default()) {
+            Err(Error::DelayRange(delay)) => assert_eq!(delay, MAX_DELAY + 1),
+            _ => panic!("A delay longer than a Gif can hold should have failed."),
+        }
+        let mut encoder = Encoder::new(10.0, Quantizer::Naive).unwrap();
+        match encoder.push_with_delay(imgs[0].clone(), MAX_DELAY + 1) {
+            Err(Error::DelayRange(delay)) => assert_eq!(delay, MAX_DELAY + 1),
+            _ => panic!("A delay longer than a Gif can hold should have failed."),
+        }
+        assert!(encoder.is_empty());
+    }
+
+    #[test]
+    fn test_error_on_writing_too_few_delays() {
+        let mut gif = engiffen(&ball_frames(), 10, Quantizer::Naive).unwrap();
+        gif.delays.pop();
+        for &lossy in &[0, 20] {
+            gif.lossy = lossy;
+            let mut bytes = vec![];
+            match gif.write(&mut bytes) {
+                Err(Error::DelayCount(frames, delays)) => assert_eq!((frames, delays), (gif.images.len(), gif.images.len() - 1)),
+                _ => panic!("Writing a Gif with fewer delays than images should have failed."),
+            }
+            assert!(bytes.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_error_on_frame_rate() {
+        for &fps in &[0.0, -10.0, f64::NAN, f64::INFINITY] {
+            match delays_for_fps(fps, 3) {
+                Err(Error::FrameRate(_)) => {},
+                _ => panic!("A frame rate of {} should have failed.", fps),
+            }
+            match Encoder::new(fps, Quantizer::Naive) {
+                Err(Error::FrameRate(_)) => {},
+                _ => panic!("A frame rate of {} should have failed.", fps),
+            }
+        }
+        match engiffen(&ball_frames(), 0, Quantizer::Naive) {
+            Err(Error::FrameRate(fps)) => assert_eq!(fps, 0.0),
+            _ => panic!("A frame rate of 0 should have failed."),
+        }
+    }
+
+    #[test]
+    fn test_encoder_error_on_no_frames() {
+        let encoder = Encoder::new(30.0, Quantizer::Naive).unwrap();
+        match encoder.finish() {
+            Err(Error::NoImages) => {},
+            _ => panic!("Finishing without frames should have failed."),
+        }
+    }
+
     #[test] #[ignore]
     fn test_compress_palette() {
         // This takes a while to run when not in --release
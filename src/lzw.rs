@@ -0,0 +1,308 @@
+//! LZW compression of palette indices into Gif image data, which can trade
+//! some accuracy for size.
+//!
+//! The encoder follows the string table as far as the pixels match one of
+//! its strings, writes the code of that string, and adds a new string of it
+//! followed by the next pixel. When lossy, it also follows strings whose next
+//! index isn't the pixel's own but whose color is close enough to the
+//! pixel's. Strings then run longer and fewer codes are written, at the cost
+//! of some pixels shifting slightly in color. A string always starts at the
+//! pixel's exact index, and the transparent index only ever matches itself,
+//! so see-through areas stay exactly where they were.
+
+/// Most codes a Gif's string table can hold.
+const MAX_CODES: usize = 4096;
+
+/// Packs codes of varying sizes into bytes, least significant bit first.
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    bits: u8,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter {
+            bytes: Vec::new(),
+            buffer: 0,
+            bits: 0,
+        }
+    }
+
+    fn write(&mut self, code: u16, size: u8) {
+        self.buffer |= (code as u32) << self.bits;
+        self.bits += size;
+        while self.bits >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+/// The string table, as a tree of codes where each child adds one index to
+/// the string of its parent. One table can be reused for every frame, so it's
+/// only allocated once.
+pub struct Table {
+    roots: usize,
+    /// Child code by parent code and index, or 0 for none
+    next: Vec<u16>,
+    /// Indices each code has children for
+    children: Vec<Vec<u8>>,
+    next_code: u16,
+}
+
+impl Table {
+    pub fn new() -> Table {
+        Table {
+            roots: 0,
+            next: Vec::new(),
+            children: vec![Vec::new(); MAX_CODES],
+            next_code: 2,
+        }
+    }
+
+    /// Empties the table for a frame with `roots` single index strings.
+    fn reset(&mut self, roots: usize) {
+        self.clear();
+        self.roots = roots;
+        if self.next.len() < MAX_CODES * roots {
+            self.next.resize(MAX_CODES * roots, 0);
+        }
+        self.next_code = roots as u16 + 2;
+    }
+
+    fn child(&self, code: u16, index: u8) -> u16 {
+        self.next[code as usize * self.roots + index as usize]
+    }
+
+    fn add(&mut self, code: u16, index: u8) {
+        self.next[code as usize * self.roots + index as usize] = self.next_code;
+        self.children[code as usize].push(index);
+        self.next_code += 1;
+    }
+
+    fn is_full(&self) -> bool {
+        self.next_code as usize == MAX_CODES
+    }
+
+    /// Removes every string added since the last clear. Only the entries
+    /// that were set are zeroed, which are the ones `children` lists.
+    fn clear(&mut self) {
+        for code in 0..self.next_code as usize {
+            for &index in &self.children[code] {
+                self.next[code * self.roots + index as usize] = 0;
+            }
+            self.children[code].clear();
+        }
+        self.next_code = self.roots as u16 + 2;
+    }
+}
+
+/// Most strings with an inexact index the lossy search tries at each step,
+/// besides the exact ones.
+const SEARCH_LIMIT: usize = 64;
+
+/// The code of the longest string in the table starting at `code` that
+/// matches the indices from `pos` on, where indices may differ from the
+/// string's if `close` says they're close enough, along with where the
+/// string ends. The exact index is tried first at every step.
+fn longest_match<F>(table: &Table, code: u16, indices: &[u8], pos: usize, close: &F) -> (u16, usize)
+    where F: Fn(u8, u8) -> bool {
+    let mut best = (code, pos);
+    let mut stack = vec![(code, pos)];
+    let mut tried = 0;
+    while let Some((code, pos)) = stack.pop() {
+        if pos > best.1 {
+            best = (code, pos);
+        }
+        let index = match indices.get(pos) {
+            Some(&index) => index,
+            None => continue,
+        };
+        for &other in &table.children[code as usize] {
+            if tried < SEARCH_LIMIT && other != index && close(index, other) {
+                stack.push((table.child(code, other), pos + 1));
+                tried += 1;
+            }
+        }
+        // Popped first
+        let exact = table.child(code, index);
+        if exact != 0 {
+            stack.push((exact, pos + 1));
+        }
+    }
+    best
+}
+
+/// Compresses the palette `indices` of a frame with the given minimum code
+/// size, which has to leave room for every index. `table` is emptied first,
+/// so the same one can be passed for every frame.
+///
+/// With a `lossy` level above 0, pixels may be encoded as a palette color up
+/// to that distance away from their own in RGB space, looked up in
+/// `palette`. `transparent` is never swapped for another index, nor any
+/// index for it.
+pub fn compress(table: &mut Table, indices: &[u8], min_code_size: u8, palette: &[u8], transparent: Option<u8>, lossy: u32) -> Vec<u8> {
+    let clear = 1u16 << min_code_size;
+    let end = clear + 1;
+    table.reset(clear as usize);
+    let mut code_size = min_code_size + 1;
+    let mut out = BitWriter::new();
+    out.write(clear, code_size);
+
+    let max_distance = lossy * lossy;
+    let color = |index: u8| {
+        let i = index as usize * 3;
+        palette.get(i..i + 3).map(|c| [c[0] as i32, c[1] as i32, c[2] as i32])
+    };
+    let distance = |a: u8, b: u8| -> Option<u32> {
+        if Some(a) == transparent || Some(b) == transparent {
+            return None;
+        }
+        match (color(a), color(b)) {
+            (Some(a), Some(b)) => Some(a.iter().zip(b.iter()).map(|(a, b)| ((a - b) * (a - b)) as u32).sum()),
+            _ => None,
+        }
+    };
+
+    let mut pos = 0;
+    while pos < indices.len() {
+        let close = |a: u8, b: u8| lossy > 0 && distance(a, b).is_some_and(|d| d <= max_distance);
+        let (code, end_pos) = longest_match(table, indices[pos] as u16, indices, pos + 1, &close);
+        pos = end_pos;
+        out.write(code, code_size);
+
+        if let Some(&index) = indices.get(pos) {
+            if table.is_full() {
+                out.write(clear, code_size);
+                table.clear();
+                code_size = min_code_size + 1;
+            } else {
+                table.add(code, index);
+                if table.next_code > 1 << code_size && code_size < 12 {
+                    code_size += 1;
+                }
+            }
+        }
+    }
+    out.write(end, code_size);
+    out.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compress, Table};
+
+    /// Decodes the codes back into indices, the way a Gif decoder would.
+    fn decompress(data: &[u8], min_code_size: u8) -> Vec<u8> {
+        let clear = 1usize << min_code_size;
+        let mut table: Vec<Vec<u8>> = Vec::new();
+        let mut code_size = min_code_size + 1;
+        let mut previous: Option<Vec<u8>> = None;
+        let mut out = Vec::new();
+        let (mut buffer, mut bits, mut bytes) = (0u32, 0u8, data.iter());
+        loop {
+            while bits < code_size {
+                buffer |= (*bytes.next().expect("Ran out of data before the end code.") as u32) << bits;
+                bits += 8;
+            }
+            let code = (buffer & ((1 << code_size) - 1)) as usize;
+            buffer >>= code_size;
+            bits -= code_size;
+
+            if code == clear {
+                table = (0..clear).map(|i| vec![i as u8]).collect();
+                table.push(vec![]);
+                table.push(vec![]);
+                code_size = min_code_size + 1;
+                previous = None;
+                continue;
+            }
+            if code == clear + 1 {
+                return out;
+            }
+            let string = match previous {
+                Some(ref previous) => {
+                    let string = if code < table.len() {
+                        table[code].clone()
+                    } else {
+                        let mut string = previous.clone();
+                        string.push(previous[0]);
+                        string
+                    };
+                    let mut entry = previous.clone();
+                    entry.push(string[0]);
+                    table.push(entry);
+                    if table.len() == 1 << code_size && code_size < 12 {
+                        code_size += 1;
+                    }
+                    string
+                },
+                None => table[code].clone(),
+            };
+            out.extend_from_slice(&string);
+            previous = Some(string);
+        }
+    }
+
+    fn gray_palette() -> Vec<u8> {
+        (0..=255u8).flat_map(|v| vec![v, v, v]).collect()
+    }
+
+    #[test]
+    fn test_lossless_round_trip() {
+        let palette = gray_palette();
+        let cases: Vec<(Vec<u8>, u8)> = vec![
+            (vec![0, 1, 0, 1, 0, 1, 1, 1, 1, 1, 1, 0], 2),
+            (vec![3; 10], 2),
+            (vec![7], 3),
+            ((0..20000u32).map(|n| ((n * 7919) % 251) as u8).collect(), 8),
+            ((0..20000u32).map(|n| ((n / 3) % 16) as u8).collect(), 4),
+        ];
+        // Reusing one table checks that it's emptied between frames
+        let mut table = Table::new();
+        for (indices, min_code_size) in cases {
+            let data = compress(&mut table, &indices, min_code_size, &palette, None, 0);
+            assert_eq!(decompress(&data, min_code_size), indices);
+        }
+    }
+
+    #[test]
+    fn test_lossy_stays_close() {
+        let palette = gray_palette();
+        // Noisy gradient that lossless compression does poorly on
+        let mut seed = 1u32;
+        let indices: Vec<u8> = (0..40000u32).map(|n| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (n % 200 / 4 * 4) as u8 + (seed >> 16) as u8 % 4
+        }).collect();
+        let lossless = compress(&mut Table::new(), &indices, 8, &palette, None, 0);
+        let lossy = compress(&mut Table::new(), &indices, 8, &palette, None, 10);
+        assert!(lossy.len() * 4 < lossless.len() * 3, "{} vs {}", lossy.len(), lossless.len());
+
+        let decoded = decompress(&lossy, 8);
+        assert_eq!(decoded.len(), indices.len());
+        for (&a, &b) in decoded.iter().zip(indices.iter()) {
+            // Gray colors are sqrt(3) apart per step
+            assert!((a as i32 - b as i32).abs() * 173 <= 1000, "{} became {}", b, a);
+        }
+    }
+
+    #[test]
+    fn test_lossy_keeps_transparency() {
+        let palette = gray_palette();
+        let indices: Vec<u8> = (0..10000u32).map(|n| if n % 7 == 0 { 1 } else { (n % 3) as u8 * 2 }).collect();
+        let decoded = decompress(&compress(&mut Table::new(), &indices, 8, &palette, Some(1), 50), 8);
+        for (&a, &b) in decoded.iter().zip(indices.iter()) {
+            assert_eq!(a == 1, b == 1);
+        }
+    }
+}
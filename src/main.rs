@@ -4,12 +4,13 @@ extern crate getopts;
 extern crate rand;
 #[cfg(feature = "globbing")] extern crate glob;
 
-use std::io::{self, BufWriter};
+use std::io::{self, BufWriter, Read, Write};
 use std::{env, fmt, process};
+use std::collections::HashMap;
 use std::fs::{read_dir, File};
 use std::path::PathBuf;
 use std::time::{Instant, Duration};
-use parse_args::{parse_args, Args, SourceImages, Modifier};
+use parse_args::{parse_args, parse_delays, Args, ArgsError, Delays, SourceImages, Modifier};
 
 #[cfg(feature = "globbing")] use self::glob::glob;
 
@@ -23,6 +24,8 @@ mod parse_args;
 enum RuntimeError {
     Directory(PathBuf),
     Destination(String),
+    DelaysFile(String, String),
+    Args(ArgsError),
     Engiffen(engiffen::Error),
 }
 
@@ -32,11 +35,19 @@ impl From<engiffen::Error> for RuntimeError {
     }
 }
 
+impl From<ArgsError> for RuntimeError {
+    fn from(err: ArgsError) -> RuntimeError {
+        RuntimeError::Args(err)
+    }
+}
+
 impl fmt::Display for RuntimeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             RuntimeError::Directory(ref dir) => write!(f, "No such directory {:?}", dir),
             RuntimeError::Destination(ref dst) => write!(f, "Couldn't write to output '{}'", dst),
+            RuntimeError::DelaysFile(ref file, ref reason) => write!(f, "Couldn't read delays from '{}': {}", file, reason),
+            RuntimeError::Args(ref e) => e.fmt(f),
             RuntimeError::Engiffen(ref e) => e.fmt(f,)
         }
     }
@@ -74,12 +85,135 @@ fn run_engiffen(args: &Args) -> Result<((Option<String>, Duration)), RuntimeErro
         },
     };
 
-    modify(&mut source_images, &args.modifiers);
+    let delays = match args.delays_file {
+        Some(ref filename) => Some(read_delays(filename)?),
+        None => None,
+    };
+
+    // A delays file that lists image paths can stand in for the source images
+    if let Some(Delays::Paths(ref paths)) = delays {
+        if source_images.is_empty() {
+            source_images = paths.iter().map(|(path, _)| path.clone()).collect();
+        }
+    }
+
+    // Every frame needs exactly one delay from the file
+    let mismatch = |reason: String| {
+        let filename = args.delays_file.clone().unwrap_or_default();
+        RuntimeError::DelaysFile(filename, reason)
+    };
+    let sources: Vec<(PathBuf, Option<u32>)> = match delays {
+        Some(Delays::Sequence(ref delays)) => {
+            if delays.len() != source_images.len() {
+                return Err(mismatch(format!("it has {} delays for {} frames", delays.len(), source_images.len())));
+            }
+            source_images.into_iter()
+                .zip(delays.iter().map(|&delay| Some(delay)))
+                .collect()
+        },
+        Some(Delays::Paths(ref paths)) => {
+            if paths.len() != source_images.len() {
+                return Err(mismatch(format!("it has {} delays for {} frames", paths.len(), source_images.len())));
+            }
+            let delays: HashMap<_, _> = paths.iter().cloned().collect();
+            source_images.into_iter()
+                .map(|path| match delays.get(&path) {
+                    Some(&delay) => Ok((path, Some(delay))),
+                    None => Err(mismatch(format!("it has no delay for {:?}", path))),
+                })
+                .collect::<Result<_, _>>()?
+        },
+        None => source_images.into_iter().map(|path| (path, None)).collect(),
+    };
+
+    // Modifiers rearrange indices into the paths, so a file that's shown more
+    // than once is still only loaded once
+    let (paths, delays): (Vec<_>, Vec<_>) = sources.into_iter().unzip();
+    let mut frames: Vec<(usize, Option<u32>)> = delays.into_iter().enumerate().collect();
+    let fps = modify(&mut frames, &args.modifiers, args.fps);
+
+    // Create the palette file before encoding, so a path that can't be
+    // written fails before all the work is done instead of after
+    let palette_out = match args.save_palette {
+        Some(ref filename) => {
+            let format = engiffen::PaletteFormat::from_path(filename)
+                .ok_or_else(|| RuntimeError::Destination(filename.to_owned()))?;
+            let file = File::create(filename)
+                .map_err(|_| RuntimeError::Destination(filename.to_owned()))?;
+            Some((BufWriter::new(file), format))
+        },
+        None => None,
+    };
+
+    let mut uses = vec![0; paths.len()];
+    for &(n, _) in &frames {
+        uses[n] += 1;
+    }
+    let mut loaded: Vec<Option<engiffen::Image>> = paths.iter().zip(uses.iter())
+        .map(|(path, &count)| if count > 0 { engiffen::load_image(path).ok() } else { None })
+        .collect();
+    // Frames shown more than once are cloned, but the last showing takes the
+    // loaded image itself
+    let (imgs, delays): (Vec<_>, Vec<_>) = frames.iter()
+        .filter_map(|&(n, delay)| {
+            uses[n] -= 1;
+            let img = if uses[n] == 0 { loaded[n].take() } else { loaded[n].clone() };
+            img.map(|img| (img, delay))
+        })
+        .unzip();
+    let delays: Vec<u32> = engiffen::delays_for_fps(fps, delays.len())?.into_iter()
+        .zip(delays)
+        .map(|(default_delay, delay)| delay.unwrap_or(default_delay))
+        .collect();
 
-    let imgs = engiffen::load_images(&source_images);
+    if args.verbose {
+        let cuts: Vec<String> = engiffen::detect_scenes(&imgs).iter()
+            .skip(1)
+            .map(|scene| (scene.start + 1).to_string())
+            .collect();
+        if cuts.is_empty() {
+            eprintln!("No scene cuts in {} frames.", imgs.len());
+        } else {
+            eprintln!("Scene cuts before frames {} of {}.", cuts.join(", "), imgs.len());
+        }
+    }
 
     let now = Instant::now();
-    let gif = engiffen::engiffen(&imgs, args.fps, args.quantizer)?;
+    let options = engiffen::Options {
+        quantizer: args.quantizer,
+        dither: args.dither,
+        dither_strength: args.dither_strength,
+        kmeans: args.kmeans,
+        colors: args.colors,
+        palette_mode: args.palette_mode,
+        alpha_threshold: args.alpha_threshold,
+        matte: args.matte.clone(),
+        dedupe: args.modifiers.contains(&Modifier::Dedupe),
+        ..engiffen::Options::default()
+    };
+    let palette = match args.palette_file {
+        Some(ref filename) => Some(engiffen::FixedPalette::load(filename)?),
+        None => None,
+    };
+    let quantizer: &dyn engiffen::Quantize = match palette {
+        Some(ref palette) => palette,
+        None => &options.quantizer,
+    };
+    let mut gif = match args.max_size {
+        Some(max_size) => {
+            let (gif, fit) = engiffen::engiffen_to_size_with_quantizer(&imgs, &delays, quantizer, &options, max_size)?;
+            eprintln!("Fit in {} bytes with {} colors, lossy {}, {} of {} frames at {}x{}.",
+                fit.size, fit.colors, fit.lossy, gif.images.len(), imgs.len(), gif.width, gif.height);
+            gif
+        },
+        None => {
+            let mut gif = engiffen::engiffen_with_quantizer(&imgs, &delays, quantizer, &options)?;
+            gif.optimize = args.optimize;
+            gif.lossy = args.lossy;
+            gif
+        },
+    };
+    gif.looping = args.looping;
     match args.out_file {
         Some(ref filename) => {
             let mut file = BufWriter::new(
@@ -94,10 +228,22 @@ fn run_engiffen(args: &Args) -> Result<((Option<String>, Duration)), RuntimeErro
             gif.write(&mut handle)
         }
     }?;
+    if let Some((mut out, format)) = palette_out {
+        gif.write_palette(&mut out, format)?;
+        out.flush().map_err(engiffen::Error::PaletteWrite)?;
+    }
     let duration = now.elapsed();
     Ok((args.out_file.clone(), duration))
 }
 
+fn read_delays(filename: &str) -> Result<Delays, RuntimeError> {
+    let mut contents = String::new();
+    File::open(filename)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|e| RuntimeError::DelaysFile(filename.to_owned(), e.to_string()))?;
+    Ok(parse_delays(&contents)?)
+}
+
 fn main() {
     let arg_strings: Vec<String> = env::args().collect();
     let args = parse_args(&arg_strings).map_err(|e| {
@@ -118,22 +264,78 @@ fn main() {
     }
 }
 
-fn modify<P>(source_images: &mut [P], modifiers: &[Modifier]) {
+/// Applies the modifiers to the frames in order, and returns the frame rate
+/// the frames left should play at to keep their timing.
+fn modify<P: Clone>(frames: &mut Vec<(P, Option<u32>)>, modifiers: &[Modifier], fps: f64) -> f64 {
+    let mut fps = fps;
     for modifier in modifiers {
         match *modifier {
-            Modifier::Reverse => reverse(source_images),
-            Modifier::Shuffle => shuffle(source_images),
+            Modifier::Reverse => reverse(frames),
+            Modifier::Shuffle => shuffle(frames),
+            // Needs the frames' pixels, so it's left to the engiffening
+            Modifier::Dedupe => {},
+            Modifier::PingPong => pingpong(frames),
+            Modifier::Every(step) => fps = decimate(frames, fps, step as f64),
+            Modifier::Fps(target) if target < fps => fps = decimate(frames, fps, fps / target),
+            // Frames are never repeated to raise the frame rate
+            Modifier::Fps(_) => {},
+            Modifier::Skip(count) => {
+                let count = count.min(frames.len());
+                frames.drain(..count);
+            },
+            Modifier::Take(count) => frames.truncate(count),
+        }
+    }
+    fps
+}
+
+/// Keeps one frame out of every `step`, which can be fractional, and returns
+/// the frame rate that keeps the timing of the frames left. Frames that have
+/// a delay of their own, or that stand in for any that do, get a delay long
+/// enough to cover every frame they stand in for, and are kept more than once
+/// if that's longer than a single frame can last.
+fn decimate<P: Clone>(frames: &mut Vec<(P, Option<u32>)>, fps: f64, step: f64) -> f64 {
+    let frame_delay = 1000.0 / fps;
+    let start_of = |k: usize| (k as f64 * step).floor() as usize;
+    let mut kept = vec![];
+    for (start, end) in (0..).map(|k| (start_of(k), start_of(k + 1).min(frames.len())))
+        .take_while(|&(start, _)| start < frames.len())
+    {
+        let group = &frames[start..end];
+        let path = &group[0].0;
+        if group.iter().any(|&(_, delay)| delay.is_some()) {
+            let total: f64 = group.iter().map(|&(_, delay)| delay.map_or(frame_delay, f64::from)).sum();
+            let mut total = total.round() as u64;
+            while total > u64::from(engiffen::MAX_DELAY) {
+                kept.push((path.clone(), Some(engiffen::MAX_DELAY)));
+                total -= u64::from(engiffen::MAX_DELAY);
+            }
+            kept.push((path.clone(), Some(total as u32)));
+        } else {
+            kept.push((path.clone(), None));
         }
     }
+    *frames = kept;
+    fps / step
 }
 
 fn reverse<T>(src: &mut [T]) {
-    let last_index = src.len()-1;
+    let last_index = src.len().saturating_sub(1);
     for n in 0..(src.len()/2) {
         src.swap(n, last_index-n);
     }
 }
 
+/// Appends the frames in reverse, leaving out the last and first so they
+/// don't show twice in a row when the gif loops. The frames are cloned, so
+/// it's run on indices into the images rather than the images themselves.
+fn pingpong<T: Clone>(src: &mut Vec<T>) {
+    if src.len() > 2 {
+        let back: Vec<T> = src[1..src.len() - 1].iter().rev().cloned().collect();
+        src.extend(back);
+    }
+}
+
 fn shuffle<T>(src: &mut [T]) {
     use std::cmp::{max, min};
 
@@ -152,3 +354,91 @@ fn shuffle<T>(src: &mut [T]) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{modify, pingpong};
+    use parse_args::Modifier;
+
+    fn frames(count: usize) -> Vec<(usize, Option<u32>)> {
+        (0..count).map(|n| (n, None)).collect()
+    }
+
+    fn paths(frames: &[(usize, Option<u32>)]) -> Vec<usize> {
+        frames.iter().map(|&(path, _)| path).collect()
+    }
+
+    #[test]
+    fn test_skip_and_take() {
+        let mut src = frames(10);
+        let fps = modify(&mut src, &[Modifier::Skip(2), Modifier::Take(5)], 30.0);
+        assert_eq!(paths(&src), vec![2, 3, 4, 5, 6]);
+        assert_eq!(fps, 30.0);
+
+        let mut src = frames(3);
+        modify(&mut src, &[Modifier::Skip(5), Modifier::Reverse], 30.0);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn test_every() {
+        let mut src = frames(10);
+        let fps = modify(&mut src, &[Modifier::Every(3)], 60.0);
+        assert_eq!(paths(&src), vec![0, 3, 6, 9]);
+        assert_eq!(src.iter().filter(|&&(_, delay)| delay.is_some()).count(), 0);
+        assert_eq!(fps, 20.0);
+    }
+
+    #[test]
+    fn test_fps() {
+        let mut src = frames(12);
+        let fps = modify(&mut src, &[Modifier::Fps(15.0)], 60.0);
+        assert_eq!(paths(&src), vec![0, 4, 8]);
+        assert_eq!(fps, 15.0);
+
+        // Steps between frames alternate to keep a fractional ratio
+        let mut src = frames(10);
+        let fps = modify(&mut src, &[Modifier::Fps(24.0)], 60.0);
+        assert_eq!(paths(&src), vec![0, 2, 5, 7]);
+        assert_eq!(fps, 24.0);
+
+        // but frames are never repeated
+        let mut src = frames(4);
+        let fps = modify(&mut src, &[Modifier::Fps(60.0)], 30.0);
+        assert_eq!((src.len(), fps), (4, 30.0));
+    }
+
+    #[test]
+    fn test_decimate_keeps_delays() {
+        let mut src = frames(6);
+        src[1].1 = Some(500);
+        src[4].1 = Some(1000);
+        modify(&mut src, &[Modifier::Every(2)], 10.0);
+        assert_eq!(src, vec![(0, Some(600)), (2, None), (4, Some(1100))]);
+
+        let max = engiffen::MAX_DELAY;
+        let mut src = frames(4);
+        src[0].1 = Some(max);
+        src[1].1 = Some(max - 50);
+        modify(&mut src, &[Modifier::Every(2)], 10.0);
+        assert_eq!(src, vec![(0, Some(max)), (0, Some(max - 50)), (2, None)]);
+    }
+
+    #[test]
+    fn test_pingpong() {
+        let mut src = vec![1, 2, 3, 4];
+        pingpong(&mut src);
+        assert_eq!(src, vec![1, 2, 3, 4, 3, 2]);
+        let mut src = vec![1, 2];
+        pingpong(&mut src);
+        assert_eq!(src, vec![1, 2]);
+
+        // It applies where it is in the list, like every other modifier
+        let mut src = frames(4);
+        modify(&mut src, &[Modifier::PingPong, Modifier::Take(5)], 10.0);
+        assert_eq!(paths(&src), vec![0, 1, 2, 3, 2]);
+        let mut src = frames(4);
+        modify(&mut src, &[Modifier::Take(3), Modifier::PingPong], 10.0);
+        assert_eq!(paths(&src), vec![0, 1, 2, 1]);
+    }
+}
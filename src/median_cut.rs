@@ -0,0 +1,152 @@
+//! Median cut color quantization.
+//!
+//! The colors of a histogram start out in one box. The box spanning the
+//! widest range of any channel is repeatedly split in two at the median
+//! pixel along that channel, until there are as many boxes as palette colors
+//! or no box can be split any further. Each box's palette color is the
+//! average of the pixels inside it.
+
+use super::Rgb;
+
+/// A range of histogram entries sharing a palette color.
+struct Cube {
+    start: usize,
+    end: usize,
+}
+
+impl Cube {
+    /// The channel with the widest range of values in the cube, and that range.
+    fn widest_channel(&self, entries: &[(Rgb, usize)]) -> (usize, u8) {
+        let entries = &entries[self.start..self.end];
+        (0..3).map(|c| {
+            let min = entries.iter().map(|e| e.0[c]).min().unwrap_or(0);
+            let max = entries.iter().map(|e| e.0[c]).max().unwrap_or(0);
+            (c, max - min)
+        }).fold((0, 0), |widest, channel| if channel.1 > widest.1 { channel } else { widest })
+    }
+
+    fn average(&self, entries: &[(Rgb, usize)]) -> Rgb {
+        let mut sums = [0u64; 3];
+        let mut count = 0u64;
+        for &(color, n) in &entries[self.start..self.end] {
+            for c in 0..3 {
+                sums[c] += color[c] as u64 * n as u64;
+            }
+            count += n as u64;
+        }
+        let count = count.max(1);
+        [
+            ((sums[0] + count / 2) / count) as u8,
+            ((sums[1] + count / 2) / count) as u8,
+            ((sums[2] + count / 2) / count) as u8,
+        ]
+    }
+}
+
+/// Computes a palette of at most `max_colors` colors for a histogram of
+/// colors and their pixel counts.
+///
+/// Returns the palette, and the index into it of every histogram entry in
+/// the histogram's order. The result only depends on the histogram's
+/// contents, not on its order.
+pub fn quantize(histogram: &[(Rgb, usize)], max_colors: usize) -> (Vec<Rgb>, Vec<u8>) {
+    let mut entries = histogram.to_vec();
+    entries.sort_by_key(|e| e.0);
+
+    let mut cubes = vec![Cube { start: 0, end: entries.len() }];
+    while cubes.len() < max_colors {
+        let widest = cubes.iter().enumerate()
+            .filter(|&(_, cube)| cube.end - cube.start > 1)
+            .map(|(n, cube)| (n, cube.widest_channel(&entries)))
+            .fold(None, |widest: Option<(usize, (usize, u8))>, cube| {
+                match widest {
+                    Some(w) if (w.1).1 >= (cube.1).1 => Some(w),
+                    _ => Some(cube),
+                }
+            });
+        // Stop once the remaining boxes each hold a single color
+        let (n, channel) = match widest {
+            Some((n, (channel, range))) if range > 0 => (n, channel),
+            _ => break,
+        };
+        let (start, end) = (cubes[n].start, cubes[n].end);
+        entries[start..end].sort_by_key(|e| (e.0[channel], e.0));
+
+        let total: usize = entries[start..end].iter().map(|e| e.1).sum();
+        let mut seen = 0;
+        let mut split = start + 1;
+        for (i, entry) in entries[start..end].iter().enumerate() {
+            seen += entry.1;
+            if seen * 2 >= total {
+                split = start + i + 1;
+                break;
+            }
+        }
+        // Both halves need at least one color
+        let split = split.max(start + 1).min(end - 1);
+        cubes[n].end = split;
+        cubes.push(Cube { start: split, end });
+    }
+
+    let palette: Vec<Rgb> = cubes.iter().map(|cube| cube.average(&entries)).collect();
+    let mut indices = vec![0u8; entries.len()];
+    let mut positions: Vec<(Rgb, u8)> = Vec::with_capacity(entries.len());
+    for (i, cube) in cubes.iter().enumerate() {
+        for entry in &entries[cube.start..cube.end] {
+            positions.push((entry.0, i as u8));
+        }
+    }
+    positions.sort_by_key(|p| p.0);
+    for (n, entry) in histogram.iter().enumerate() {
+        let found = positions.binary_search_by(|p| p.0.cmp(&entry.0)).expect("A histogram color is missing from every cube.");
+        indices[n] = positions[found].1;
+    }
+    (palette, indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::quantize;
+
+    #[test]
+    fn test_exact_when_colors_fit() {
+        let histogram = vec![([255, 0, 0], 10), ([0, 255, 0], 1), ([0, 0, 255], 5)];
+        let (palette, indices) = quantize(&histogram, 256);
+        assert_eq!(palette.len(), 3);
+        for (n, entry) in histogram.iter().enumerate() {
+            assert_eq!(palette[indices[n] as usize], entry.0);
+        }
+    }
+
+    #[test]
+    fn test_limits_colors() {
+        let histogram: Vec<_> = (0..1000).map(|n| {
+            ([(n % 256) as u8, (n * 7 % 256) as u8, (n * 13 % 256) as u8], n % 5 + 1)
+        }).collect();
+        let (palette, indices) = quantize(&histogram, 16);
+        assert_eq!(palette.len(), 16);
+        assert!(indices.iter().all(|&i| i < 16));
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let mut histogram: Vec<_> = (0..500).map(|n| {
+            ([(n % 256) as u8, (n / 256 * 128) as u8, (n * 17 % 256) as u8], n % 3 + 1)
+        }).collect();
+        let (palette, indices) = quantize(&histogram, 32);
+        histogram.reverse();
+        let (reversed_palette, mut reversed_indices) = quantize(&histogram, 32);
+        reversed_indices.reverse();
+        assert_eq!(palette, reversed_palette);
+        assert_eq!(indices, reversed_indices);
+    }
+
+    #[test]
+    fn test_splits_at_weighted_median() {
+        // Half the pixels are black, so black gets a box to itself
+        let histogram = vec![([0, 0, 0], 100), ([100, 0, 0], 1), ([200, 0, 0], 1), ([255, 0, 0], 1)];
+        let (palette, indices) = quantize(&histogram, 2);
+        assert_eq!(palette, vec![[0, 0, 0], [185, 0, 0]]);
+        assert_eq!(indices, vec![0, 1, 1, 1]);
+    }
+}
@@ -0,0 +1,343 @@
+//! Octree color quantization.
+//!
+//! Colors are sorted into a tree eight levels deep, where each level looks at
+//! one more bit of red, green and blue to pick which of eight children a
+//! color falls into. Leaves hold the sum and number of the pixels that ended
+//! up in them. Whenever there are too many leaves, the deepest node with the
+//! fewest pixels has its children folded into it, so the tree never holds
+//! more than a fixed number of leaves no matter how many colors are added.
+//! Once a level has been folded, new leaves aren't created any deeper than
+//! it, so later colors don't grow branches that would only be folded again.
+//!
+//! The palette is made by folding nodes the same way, but where folding all
+//! of a node's children would leave fewer leaves than there are colors to
+//! fill, only its two smallest children are merged, so the palette gets as
+//! many colors as it asks for.
+
+use super::Rgb;
+
+const DEPTH: usize = 8;
+
+#[derive(Debug, Clone)]
+struct Node {
+    children: [Option<usize>; 8],
+    sums: [u64; 3],
+    count: u64,
+    depth: usize,
+    leaf: bool,
+    index: u8,
+}
+
+impl Node {
+    fn new(depth: usize, leaf: bool) -> Node {
+        Node {
+            children: [None; 8],
+            sums: [0; 3],
+            count: 0,
+            depth,
+            leaf,
+            index: 0,
+        }
+    }
+
+    fn color(&self) -> Rgb {
+        let count = self.count.max(1);
+        [
+            ((self.sums[0] + count / 2) / count) as u8,
+            ((self.sums[1] + count / 2) / count) as u8,
+            ((self.sums[2] + count / 2) / count) as u8,
+        ]
+    }
+}
+
+/// Which child of a node at `depth` a color falls into.
+fn child_index(color: &Rgb, depth: usize) -> usize {
+    let shift = 7 - depth;
+    (((color[0] >> shift) & 1) << 2 | ((color[1] >> shift) & 1) << 1 | ((color[2] >> shift) & 1)) as usize
+}
+
+/// An octree holding at most `max_leaves` leaves.
+#[derive(Debug, Clone)]
+pub struct Octree {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    /// Internal nodes at each depth
+    levels: Vec<Vec<usize>>,
+    leaves: usize,
+    max_leaves: usize,
+    leaf_depth: usize,
+}
+
+impl Octree {
+    pub fn new(max_leaves: usize) -> Octree {
+        Octree {
+            nodes: vec![Node::new(0, false)],
+            free: Vec::new(),
+            levels: {
+                let mut levels = vec![Vec::new(); DEPTH];
+                levels[0].push(0);
+                levels
+            },
+            leaves: 0,
+            max_leaves: max_leaves.max(8),
+            leaf_depth: DEPTH,
+        }
+    }
+
+    /// Adds one pixel of `color`.
+    pub fn add(&mut self, color: Rgb) {
+        let sums = [color[0] as u64, color[1] as u64, color[2] as u64];
+        self.insert(&color, sums, 1);
+    }
+
+    /// Adds every pixel from another octree.
+    pub fn merge(&mut self, other: Octree) {
+        for node in other.nodes.iter().filter(|node| node.leaf && node.count > 0) {
+            self.insert(&node.color(), node.sums, node.count);
+        }
+    }
+
+    /// Reduces the tree to `max_colors` leaves, or fewer if it has fewer
+    /// colors, and returns their colors. After this, `index_of` finds a
+    /// color's index in the palette.
+    pub fn palette(&mut self, max_colors: usize) -> Vec<Rgb> {
+        let max_colors = max_colors.max(1);
+        while self.leaves > max_colors {
+            let (depth, position) = match self.fewest() {
+                Some(fewest) => fewest,
+                None => break,
+            };
+            let n = self.levels[depth][position];
+            let children = self.children(n);
+            if children.len() - 1 <= self.leaves - max_colors {
+                self.fold(depth, position);
+            } else {
+                self.merge_children(n, &children);
+            }
+        }
+        let mut palette = Vec::with_capacity(self.leaves);
+        let mut seen = vec![false; self.nodes.len()];
+        let mut stack = vec![0];
+        while let Some(n) = stack.pop() {
+            if self.nodes[n].leaf {
+                if self.nodes[n].count > 0 && !seen[n] {
+                    seen[n] = true;
+                    self.nodes[n].index = palette.len() as u8;
+                    palette.push(self.nodes[n].color());
+                }
+            } else {
+                stack.extend(self.nodes[n].children.iter().rev().filter_map(|c| *c));
+            }
+        }
+        palette
+    }
+
+    /// Index in the palette of the leaf that `color` falls into, if the
+    /// tree has one for it.
+    pub fn index_of(&self, color: &Rgb) -> Option<u8> {
+        let mut n = 0;
+        loop {
+            let node = &self.nodes[n];
+            if node.leaf {
+                return if node.count > 0 { Some(node.index) } else { None };
+            }
+            n = node.children[child_index(color, node.depth)]?;
+        }
+    }
+
+    fn insert(&mut self, color: &Rgb, sums: [u64; 3], count: u64) {
+        let mut n = 0;
+        while !self.nodes[n].leaf {
+            let depth = self.nodes[n].depth;
+            let child = child_index(color, depth);
+            n = match self.nodes[n].children[child] {
+                Some(c) => c,
+                None => {
+                    let leaf = depth + 1 >= self.leaf_depth;
+                    let c = self.allocate(Node::new(depth + 1, leaf));
+                    if !leaf {
+                        self.levels[depth + 1].push(c);
+                    }
+                    self.nodes[n].children[child] = Some(c);
+                    c
+                },
+            };
+        }
+        let node = &mut self.nodes[n];
+        if node.count == 0 {
+            self.leaves += 1;
+        }
+        for (sum, added) in node.sums.iter_mut().zip(sums.iter()) {
+            *sum += added;
+        }
+        node.count += count;
+
+        while self.leaves > self.max_leaves {
+            self.reduce();
+        }
+    }
+
+    fn allocate(&mut self, node: Node) -> usize {
+        match self.free.pop() {
+            Some(n) => {
+                self.nodes[n] = node;
+                n
+            },
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            },
+        }
+    }
+
+    /// Folds the children of the deepest node with the fewest pixels into
+    /// it, making it a leaf.
+    fn reduce(&mut self) {
+        if let Some((depth, position)) = self.fewest() {
+            self.fold(depth, position);
+        }
+    }
+
+    /// Depth and position within its level of the deepest internal node
+    /// with the fewest pixels.
+    fn fewest(&self) -> Option<(usize, usize)> {
+        let depth = self.levels.iter().rposition(|level| !level.is_empty())?;
+        let position = self.levels[depth].iter().enumerate()
+            .map(|(i, &n)| (i, self.children(n).iter().map(|&c| self.nodes[c].count).sum::<u64>()))
+            .fold((0, u64::MAX), |fewest, node| if node.1 < fewest.1 { node } else { fewest })
+            .0;
+        Some((depth, position))
+    }
+
+    /// Children of node `n`, each listed once, though merged children fill
+    /// more than one slot.
+    fn children(&self, n: usize) -> Vec<usize> {
+        let mut children = Vec::with_capacity(8);
+        for c in self.nodes[n].children.iter().filter_map(|c| *c) {
+            if !children.contains(&c) {
+                children.push(c);
+            }
+        }
+        children
+    }
+
+    /// Folds the children of the internal node at `position` in the level
+    /// at `depth` into it, making it a leaf.
+    fn fold(&mut self, depth: usize, position: usize) {
+        let n = self.levels[depth].swap_remove(position);
+        self.leaf_depth = self.leaf_depth.min(depth + 1);
+
+        for c in self.children(n) {
+            self.absorb(n, c);
+        }
+        let node = &mut self.nodes[n];
+        node.children = [None; 8];
+        node.leaf = true;
+        self.leaves += 1;
+    }
+
+    /// Merges the two children of node `n` with the fewest pixels into one
+    /// leaf, which takes both their slots. The children must be leaves.
+    fn merge_children(&mut self, n: usize, children: &[usize]) {
+        let mut children = children.to_vec();
+        children.sort_by_key(|&c| self.nodes[c].count);
+        let (kept, merged) = (children[0], children[1]);
+        self.absorb(kept, merged);
+        for slot in self.nodes[n].children.iter_mut() {
+            if *slot == Some(merged) {
+                *slot = Some(kept);
+            }
+        }
+    }
+
+    /// Adds the pixels of node `c` to node `n`, and frees `c`.
+    fn absorb(&mut self, n: usize, c: usize) {
+        let (sums, count) = (self.nodes[c].sums, self.nodes[c].count);
+        let node = &mut self.nodes[n];
+        for (sum, added) in node.sums.iter_mut().zip(sums.iter()) {
+            *sum += added;
+        }
+        node.count += count;
+        self.nodes[c].count = 0;
+        self.leaves -= 1;
+        self.free.push(c);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Octree;
+
+    #[test]
+    fn test_exact_when_colors_fit() {
+        let mut tree = Octree::new(256);
+        let colors = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [10, 20, 30]];
+        for color in &colors {
+            tree.add(*color);
+            tree.add(*color);
+        }
+        let palette = tree.palette(256);
+        assert_eq!(palette.len(), 4);
+        for color in &colors {
+            assert_eq!(palette[tree.index_of(color).unwrap() as usize], *color);
+        }
+        assert_eq!(tree.index_of(&[128, 128, 128]), None);
+    }
+
+    #[test]
+    fn test_bounded_leaves() {
+        let mut tree = Octree::new(64);
+        for r in 0..64 {
+            for g in 0..64 {
+                for b in 0..64 {
+                    tree.add([r * 4, g * 4, b * 4]);
+                }
+            }
+        }
+        assert!(tree.leaves <= 64);
+        assert!(tree.nodes.len() <= (64 + 1) * 8 + 1);
+
+        let palette = tree.palette(16);
+        assert_eq!(palette.len(), 16);
+        for &color in &[[0, 0, 0], [255, 255, 255], [100, 200, 50]] {
+            assert!((tree.index_of(&color).unwrap() as usize) < palette.len());
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut left = Octree::new(256);
+        let mut right = Octree::new(256);
+        let mut both = Octree::new(256);
+        for n in 0..100u8 {
+            let color = [n, n.wrapping_mul(3), n.wrapping_mul(7)];
+            if n % 2 == 0 { left.add(color) } else { right.add(color) }
+            both.add(color);
+        }
+        left.merge(right);
+        assert_eq!(left.palette(32), both.palette(32));
+    }
+
+    #[test]
+    fn test_palette_has_the_colors_asked_for() {
+        let mut tree = Octree::new(1024);
+        let mut seed = 7u32;
+        for _ in 0..100_000 {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let v = seed >> 8;
+            tree.add([v as u8, (v >> 8) as u8, (v >> 16) as u8 / 2]);
+        }
+        for &colors in &[256, 200, 128, 64, 16, 5, 2, 1] {
+            let mut tree = tree.clone();
+            let palette = tree.palette(colors);
+            assert_eq!(palette.len(), colors);
+            let mut seed = 11u32;
+            for _ in 0..1000 {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                let v = seed >> 8;
+                let color = [v as u8, (v >> 8) as u8, (v >> 16) as u8 / 2];
+                assert!((tree.index_of(&color).unwrap() as usize) < colors);
+            }
+        }
+    }
+}
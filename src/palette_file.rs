@@ -0,0 +1,545 @@
+//! Reading and writing palettes as GIMP `.gpl` files, Adobe `.act` color
+//! tables, lists of hex colors, and swatch images.
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use image::ColorType;
+use image::png::PNGEncoder;
+use lab::Lab;
+use super::{closest_lab, load_image, Error, Gif, Image, Palette, Quantize, RGBA};
+
+/// Palette file formats.
+///
+/// `Gpl` is a GIMP palette, `Act` an Adobe color table, `Hex` a list of hex
+/// colors with one per line, and `Swatch` a PNG image with a pixel for every
+/// color, 16 to a row.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub enum PaletteFormat {
+    Gpl,
+    Act,
+    Hex,
+    Swatch,
+}
+
+impl PaletteFormat {
+    /// The format of a palette file, going by its extension: `.gpl`, `.act`,
+    /// `.hex` or `.txt`, and `.png` for a swatch image. Returns `None` for
+    /// any other extension.
+    pub fn from_path<P>(path: P) -> Option<PaletteFormat>
+        where P: AsRef<Path> {
+        let extension = path.as_ref().extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+        match extension.as_deref() {
+            Some("gpl") => Some(PaletteFormat::Gpl),
+            Some("act") => Some(PaletteFormat::Act),
+            Some("hex") | Some("txt") => Some(PaletteFormat::Hex),
+            Some("png") => Some(PaletteFormat::Swatch),
+            _ => None,
+        }
+    }
+}
+
+/// A palette fixed ahead of time, which frames are mapped onto instead of
+/// computing a palette from them.
+///
+/// Pixels are mapped to the closest palette color in Lab space. If the
+/// frames have transparent pixels and the palette doesn't name a transparent
+/// color, one is added after the palette's colors, unless the palette
+/// already has 256 colors. Every color is kept, whatever palette size the
+/// `Options` ask for.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use engiffen::{load_images, engiffen_with_quantizer, delays_for_fps, FixedPalette, Gif, Error, Options};
+/// # fn foo() -> Result<Gif, Error> {
+/// let palette = FixedPalette::load("brand.gpl")?;
+/// let images = load_images(&["tests/ball/ball01.bmp", "tests/ball/ball02.bmp"]);
+/// let delays = delays_for_fps(10.0, images.len())?;
+/// let gif = engiffen_with_quantizer(&images, &delays, &palette, &Options::default())?;
+/// # Ok(gif)
+/// # }
+/// ```
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
+pub struct FixedPalette {
+    colors: Vec<[u8; 3]>,
+    transparency: Option<u8>,
+}
+
+impl FixedPalette {
+    /// A palette of up to 256 colors.
+    ///
+    /// # Errors
+    ///
+    /// Returns Error::PaletteFormat if there are no colors or more than 256.
+    pub fn new(colors: Vec<[u8; 3]>) -> Result<FixedPalette, Error> {
+        if colors.is_empty() {
+            return Err(Error::PaletteFormat("The palette has no colors".to_owned()));
+        }
+        if colors.len() > 256 {
+            return Err(Error::PaletteFormat(format!("The palette has {} colors, but gifs can only use 256", colors.len())));
+        }
+        Ok(FixedPalette {
+            colors,
+            transparency: None,
+        })
+    }
+
+    /// The palette's colors.
+    pub fn colors(&self) -> &[[u8; 3]] {
+        &self.colors
+    }
+
+    /// Index of the color set aside for transparent pixels, if the palette
+    /// has one.
+    pub fn transparency(&self) -> Option<u8> {
+        self.transparency
+    }
+
+    /// Loads a palette from a file. Files ending in `.gpl` are read as GIMP
+    /// palettes, `.act` as Adobe color tables, and `.hex` or `.txt` as lists
+    /// of hex colors. Anything else is loaded as a swatch image.
+    ///
+    /// # Errors
+    ///
+    /// Returns Error::PaletteLoad if the file can't be read, Error::ImageLoad
+    /// if a swatch image can't be loaded, and Error::PaletteFormat if the
+    /// file isn't a valid palette.
+    pub fn load<P>(path: P) -> Result<FixedPalette, Error>
+        where P: AsRef<Path> {
+        let read = |path: &Path| -> Result<Vec<u8>, Error> {
+            let mut contents = Vec::new();
+            File::open(path)
+                .and_then(|mut file| file.read_to_end(&mut contents))
+                .map_err(Error::PaletteLoad)?;
+            Ok(contents)
+        };
+        let text = |contents: Vec<u8>| -> Result<String, Error> {
+            String::from_utf8(contents).map_err(|_| Error::PaletteFormat("The palette isn't valid UTF-8 text".to_owned()))
+        };
+        match PaletteFormat::from_path(&path).unwrap_or(PaletteFormat::Swatch) {
+            PaletteFormat::Gpl => FixedPalette::from_gpl(&text(read(path.as_ref())?)?),
+            PaletteFormat::Act => FixedPalette::from_act(&read(path.as_ref())?),
+            PaletteFormat::Hex => FixedPalette::from_hex(&text(read(path.as_ref())?)?),
+            PaletteFormat::Swatch => FixedPalette::from_image(&load_image(path)?),
+        }
+    }
+
+    /// Saves the palette to a file, in the format its extension calls for:
+    /// `.gpl`, `.act`, `.hex` or `.txt`, or `.png` for a swatch image.
+    ///
+    /// # Errors
+    ///
+    /// Returns Error::PaletteFormat if the file has any other extension, and
+    /// Error::PaletteWrite if the file can't be written.
+    pub fn save<P>(&self, path: P) -> Result<(), Error>
+        where P: AsRef<Path> {
+        let format = PaletteFormat::from_path(&path).ok_or_else(|| {
+            Error::PaletteFormat(format!("Can't tell which format to save {:?} in, use a .gpl, .act, .hex, .txt or .png extension", path.as_ref()))
+        })?;
+        let file = File::create(&path).map_err(Error::PaletteWrite)?;
+        let mut out = BufWriter::new(file);
+        self.write(&mut out, format)?;
+        out.flush().map_err(Error::PaletteWrite)
+    }
+
+    /// Writes the palette in the given format. Only Adobe color tables can
+    /// hold the transparent color, so it's left out of the other formats.
+    ///
+    /// # Errors
+    ///
+    /// Returns Error::PaletteWrite if writing fails.
+    pub fn write<W: Write>(&self, out: &mut W, format: PaletteFormat) -> Result<(), Error> {
+        let transparency = self.transparency;
+        let opaque: Vec<[u8; 3]> = self.colors.iter()
+            .enumerate()
+            .filter(|&(n, _)| Some(n as u8) != transparency)
+            .map(|(_, c)| *c)
+            .collect();
+        match format {
+            PaletteFormat::Gpl => {
+                writeln!(out, "GIMP Palette\nName: engiffen\nColumns: 16\n#").map_err(Error::PaletteWrite)?;
+                for c in &opaque {
+                    writeln!(out, "{:3} {:3} {:3}\t#{:02X}{:02X}{:02X}", c[0], c[1], c[2], c[0], c[1], c[2]).map_err(Error::PaletteWrite)?;
+                }
+            },
+            PaletteFormat::Act => {
+                let mut table = vec![0u8; 772];
+                for (n, c) in self.colors.iter().enumerate() {
+                    table[n * 3..n * 3 + 3].copy_from_slice(c);
+                }
+                let count = self.colors.len();
+                let transparency = transparency.map_or(0xFFFF, |t| t as usize);
+                table[768..].copy_from_slice(&[(count >> 8) as u8, count as u8, (transparency >> 8) as u8, transparency as u8]);
+                out.write_all(&table).map_err(Error::PaletteWrite)?;
+            },
+            PaletteFormat::Hex => {
+                for c in &opaque {
+                    writeln!(out, "#{:02X}{:02X}{:02X}", c[0], c[1], c[2]).map_err(Error::PaletteWrite)?;
+                }
+            },
+            PaletteFormat::Swatch => {
+                let width = opaque.len().clamp(1, 16);
+                let height = opaque.len().div_ceil(width).max(1);
+                let mut pixels = vec![0u8; width * height * 4];
+                for (n, c) in opaque.iter().enumerate() {
+                    pixels[n * 4..n * 4 + 3].copy_from_slice(c);
+                    pixels[n * 4 + 3] = 255;
+                }
+                PNGEncoder::new(out).encode(&pixels, width as u32, height as u32, ColorType::RGBA(8))
+                    .map_err(Error::PaletteWrite)?;
+            },
+        }
+        Ok(())
+    }
+
+    /// Reads a GIMP palette. Each color is a line of red, green and blue
+    /// values from 0 to 255, optionally followed by a name.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use engiffen::FixedPalette;
+    /// let palette = FixedPalette::from_gpl("GIMP Palette\nName: Brand\n#\n255 0 0 Red\n  0 0 255 Blue\n").unwrap();
+    /// assert_eq!(palette.colors(), &[[255, 0, 0], [0, 0, 255]]);
+    /// ```
+    pub fn from_gpl(contents: &str) -> Result<FixedPalette, Error> {
+        let mut lines = contents.lines().enumerate();
+        match lines.next() {
+            Some((_, header)) if header.trim() == "GIMP Palette" => {},
+            _ => return Err(Error::PaletteFormat("GIMP palettes must start with `GIMP Palette`".to_owned())),
+        }
+        let mut colors = Vec::new();
+        for (n, line) in lines {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("Name:") || line.starts_with("Columns:") {
+                continue;
+            }
+            let values: Vec<_> = line.split_whitespace().take(3).map(|v| v.parse::<u8>()).collect();
+            match values.as_slice() {
+                [Ok(r), Ok(g), Ok(b)] => colors.push([*r, *g, *b]),
+                _ => return Err(Error::PaletteFormat(format!("Line {}: expected a color like `255 128 0`, got `{}`", n + 1, line))),
+            }
+        }
+        FixedPalette::new(colors)
+    }
+
+    /// Reads an Adobe color table, which holds 256 colors as red, green and
+    /// blue bytes. Tables can end with 4 more bytes: the number of colors in
+    /// use, then the transparent index, both as big-endian 16 bit numbers,
+    /// where an index of 0xFFFF means there is none.
+    pub fn from_act(contents: &[u8]) -> Result<FixedPalette, Error> {
+        if contents.len() != 768 && contents.len() != 772 {
+            return Err(Error::PaletteFormat(format!("Adobe color tables are 768 or 772 bytes long, not {}", contents.len())));
+        }
+        let (count, transparency) = if contents.len() == 772 {
+            let count = (contents[768] as usize) << 8 | contents[769] as usize;
+            let transparency = (contents[770] as usize) << 8 | contents[771] as usize;
+            (if count == 0 { 256 } else { count.min(256) }, transparency)
+        } else {
+            (256, 0xFFFF)
+        };
+        let colors = contents[..count * 3].chunks(3).map(|c| [c[0], c[1], c[2]]).collect();
+        let mut palette = FixedPalette::new(colors)?;
+        if transparency < count {
+            palette.transparency = Some(transparency as u8);
+        }
+        Ok(palette)
+    }
+
+    /// Reads a list of hex colors like `#FF8000` or `f80`, one or more per
+    /// line. Eight digit colors are read as `AARRGGBB`, as in Paint.NET
+    /// palettes, and their alpha is ignored. Anything after a `;` is a
+    /// comment.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use engiffen::FixedPalette;
+    /// let palette = FixedPalette::from_hex("; Brand colors\n#FF8000\n0af, FFFFFFFF\n").unwrap();
+    /// assert_eq!(palette.colors(), &[[255, 128, 0], [0, 170, 255], [255, 255, 255]]);
+    /// ```
+    pub fn from_hex(contents: &str) -> Result<FixedPalette, Error> {
+        let mut colors = Vec::new();
+        for (n, line) in contents.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or("");
+            for word in line.split(|c: char| c.is_whitespace() || c == ',').filter(|w| !w.is_empty()) {
+                match parse_hex(word) {
+                    Some(color) => colors.push(color),
+                    None => return Err(Error::PaletteFormat(format!("Line {}: `{}` isn't a hex color", n + 1, word))),
+                }
+            }
+        }
+        FixedPalette::new(colors)
+    }
+
+    /// Reads the colors of a swatch image, in the order they first appear
+    /// going left to right and top to bottom. Transparent pixels are skipped.
+    pub fn from_image(img: &Image) -> Result<FixedPalette, Error> {
+        let mut colors: Vec<[u8; 3]> = Vec::new();
+        for px in img.pixels.iter().filter(|px| px[3] != 0) {
+            let color = [px[0], px[1], px[2]];
+            if !colors.contains(&color) {
+                if colors.len() == 256 {
+                    return Err(Error::PaletteFormat("The swatch image has more than 256 colors".to_owned()));
+                }
+                colors.push(color);
+            }
+        }
+        FixedPalette::new(colors)
+    }
+}
+
+/// Parses `RGB`, `RRGGBB` or `AARRGGBB` hex digits, optionally after a `#`.
+pub fn parse_hex(word: &str) -> Option<[u8; 3]> {
+    let digits = word.strip_prefix('#').unwrap_or(word);
+    if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let byte = |i: usize| u8::from_str_radix(&digits[i..i + 2], 16).ok();
+    match digits.len() {
+        3 => {
+            let nibble = |i: usize| u8::from_str_radix(&digits[i..i + 1], 16).ok().map(|v| v * 17);
+            Some([nibble(0)?, nibble(1)?, nibble(2)?])
+        },
+        6 => Some([byte(0)?, byte(2)?, byte(4)?]),
+        8 => Some([byte(2)?, byte(4)?, byte(6)?]),
+        _ => None,
+    }
+}
+
+impl<'a> From<&'a Gif> for FixedPalette {
+    /// The palette of a `Gif`, for mapping other `Gif`s onto the same colors.
+    /// Bytes left over after the last whole color are ignored.
+    fn from(gif: &'a Gif) -> FixedPalette {
+        FixedPalette {
+            colors: gif.palette.chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect(),
+            transparency: gif.transparency,
+        }
+    }
+}
+
+impl Quantize for FixedPalette {
+    fn palette(&self, imgs: &[Image], _max_colors: usize) -> Palette {
+        let mut colors: Vec<u8> = self.colors.iter().flat_map(|c| c.iter().cloned()).collect();
+        let mut transparency = self.transparency;
+        if transparency.is_none() && self.colors.len() < 256 {
+            let has_transparency = imgs.iter().any(|img| img.pixels.iter().any(|px| px[3] == 0));
+            if has_transparency {
+                transparency = Some(self.colors.len() as u8);
+                colors.extend_from_slice(&[0, 0, 0]);
+            }
+        }
+
+        let (indices, opaque): (Vec<u8>, Vec<(RGBA, Lab)>) = self.colors.iter()
+            .enumerate()
+            .filter(|&(n, _)| Some(n as u8) != transparency)
+            .map(|(n, c)| {
+                let rgba = [c[0], c[1], c[2], 255];
+                (n as u8, (rgba, Lab::from_rgba(&rgba)))
+            })
+            .unzip();
+        let index_of = move |px: &RGBA| {
+            match transparency {
+                Some(index) if px[3] == 0 => index,
+                _ => indices[closest_lab(&opaque, &Lab::from_rgba(px))],
+            }
+        };
+
+        Palette {
+            colors,
+            transparency,
+            index_of: Box::new(index_of),
+        }
+    }
+
+    fn is_fixed(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_hex, FixedPalette, PaletteFormat};
+    use super::super::{Error, Image, Quantize};
+
+    #[test]
+    fn test_gpl() {
+        let contents = "GIMP Palette\nName: Test\nColumns: 4\n#\n  0   0   0\tBlack\n255 255 255\tWhite\n\n128 64 32\n";
+        let palette = FixedPalette::from_gpl(contents).unwrap();
+        assert_eq!(palette.colors(), &[[0, 0, 0], [255, 255, 255], [128, 64, 32]]);
+        assert_eq!(palette.transparency(), None);
+    }
+
+    #[test]
+    fn test_gpl_errors() {
+        match FixedPalette::from_gpl("255 0 0\n") {
+            Err(Error::PaletteFormat(_)) => {},
+            other => panic!("Expected a format error, got {:?}", other),
+        }
+        match FixedPalette::from_gpl("GIMP Palette\n255 0 0\n300 0 0\n") {
+            Err(Error::PaletteFormat(message)) => assert!(message.starts_with("Line 3:")),
+            other => panic!("Expected a format error, got {:?}", other),
+        }
+        match FixedPalette::from_gpl("GIMP Palette\n") {
+            Err(Error::PaletteFormat(_)) => {},
+            other => panic!("Expected a format error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_act() {
+        let mut contents = vec![0u8; 768];
+        contents[..6].copy_from_slice(&[255, 0, 0, 0, 255, 0]);
+        let palette = FixedPalette::from_act(&contents).unwrap();
+        assert_eq!(palette.colors().len(), 256);
+        assert_eq!(palette.colors()[1], [0, 255, 0]);
+
+        contents.extend_from_slice(&[0, 3, 0, 2]);
+        let palette = FixedPalette::from_act(&contents).unwrap();
+        assert_eq!(palette.colors(), &[[255, 0, 0], [0, 255, 0], [0, 0, 0]]);
+        assert_eq!(palette.transparency(), Some(2));
+
+        let len = contents.len();
+        contents[len - 2..].copy_from_slice(&[0xFF, 0xFF]);
+        assert_eq!(FixedPalette::from_act(&contents).unwrap().transparency(), None);
+
+        assert!(FixedPalette::from_act(&[0; 12]).is_err());
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(parse_hex("#FF8000"), Some([255, 128, 0]));
+        assert_eq!(parse_hex("ff8000"), Some([255, 128, 0]));
+        assert_eq!(parse_hex("#f80"), Some([255, 136, 0]));
+        assert_eq!(parse_hex("80FF8000"), Some([255, 128, 0]));
+        assert_eq!(parse_hex("#GG0000"), None);
+        assert_eq!(parse_hex("#FF80"), None);
+        assert_eq!(parse_hex("#ÿÿÿ"), None);
+
+        let palette = FixedPalette::from_hex("#000000 #FFFFFF ; black and white\n\n; red\n#FF0000\n").unwrap();
+        assert_eq!(palette.colors(), &[[0, 0, 0], [255, 255, 255], [255, 0, 0]]);
+        match FixedPalette::from_hex("#000000\nblue\n") {
+            Err(Error::PaletteFormat(message)) => assert!(message.starts_with("Line 2:")),
+            other => panic!("Expected a format error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_swatch_image() {
+        let img = Image {
+            pixels: vec![[255, 0, 0, 255], [0, 0, 0, 0], [255, 0, 0, 255], [0, 0, 255, 255]],
+            width: 2,
+            height: 2,
+        };
+        let palette = FixedPalette::from_image(&img).unwrap();
+        assert_eq!(palette.colors(), &[[255, 0, 0], [0, 0, 255]]);
+
+        let img = Image {
+            pixels: (0..257u32).map(|n| [n as u8, (n >> 8) as u8, 0, 255]).collect(),
+            width: 257,
+            height: 1,
+        };
+        assert!(FixedPalette::from_image(&img).is_err());
+    }
+
+    #[test]
+    fn test_load() {
+        use std::env::temp_dir;
+        use std::fs::{remove_file, File};
+        use std::io::Write;
+
+        let files: Vec<(&str, Vec<u8>)> = vec![
+            ("engiffen-test-palette.gpl", b"GIMP Palette\n255 0 0\n0 0 255\n".to_vec()),
+            ("engiffen-test-palette.HEX", b"#FF0000\n#0000FF\n".to_vec()),
+            ("engiffen-test-palette.act", {
+                let mut act = vec![0u8; 772];
+                act[..6].copy_from_slice(&[255, 0, 0, 0, 0, 255]);
+                act[768..].copy_from_slice(&[0, 2, 0xFF, 0xFF]);
+                act
+            }),
+        ];
+        for (name, contents) in files {
+            let path = temp_dir().join(name);
+            File::create(&path).unwrap().write_all(&contents).unwrap();
+            let palette = FixedPalette::load(&path);
+            remove_file(&path).unwrap();
+            assert_eq!(palette.unwrap().colors(), &[[255, 0, 0], [0, 0, 255]]);
+        }
+
+        // The ball frames have too many colors to be a swatch
+        match FixedPalette::load("tests/ball/ball01.bmp") {
+            Err(Error::PaletteFormat(_)) => {},
+            other => panic!("Expected a format error, got {:?}", other),
+        }
+        match FixedPalette::load("tests/nonexistent.gpl") {
+            Err(Error::PaletteLoad(_)) => {},
+            other => panic!("Expected a load error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_write() {
+        let mut palette = FixedPalette::new(vec![[255, 0, 0], [0, 0, 0], [0, 128, 255]]).unwrap();
+        palette.transparency = Some(1);
+
+        let mut gpl = vec![];
+        palette.write(&mut gpl, PaletteFormat::Gpl).unwrap();
+        assert_eq!(String::from_utf8(gpl).unwrap(), "GIMP Palette\nName: engiffen\nColumns: 16\n#\n255   0   0\t#FF0000\n  0 128 255\t#0080FF\n");
+
+        let mut hex = vec![];
+        palette.write(&mut hex, PaletteFormat::Hex).unwrap();
+        assert_eq!(String::from_utf8(hex).unwrap(), "#FF0000\n#0080FF\n");
+
+        let mut act = vec![];
+        palette.write(&mut act, PaletteFormat::Act).unwrap();
+        assert_eq!(FixedPalette::from_act(&act).unwrap(), palette);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        use std::env::temp_dir;
+        use std::fs::remove_file;
+
+        let colors: Vec<[u8; 3]> = (0..20u8).map(|n| [n * 12, 255 - n * 12, n]).collect();
+        let palette = FixedPalette::new(colors).unwrap();
+        for name in &["engiffen-test-save.gpl", "engiffen-test-save.act", "engiffen-test-save.hex", "engiffen-test-save.png"] {
+            let path = temp_dir().join(name);
+            palette.save(&path).unwrap();
+            let loaded = FixedPalette::load(&path);
+            remove_file(&path).unwrap();
+            assert_eq!(loaded.unwrap(), palette, "{} didn't round trip", name);
+        }
+        assert_eq!(PaletteFormat::from_path("colors.TXT"), Some(PaletteFormat::Hex));
+        assert_eq!(PaletteFormat::from_path("colors"), None);
+
+        for name in &["engiffen-test-save.gif", "engiffen-test-save"] {
+            let path = temp_dir().join(name);
+            match palette.save(&path) {
+                Err(Error::PaletteFormat(_)) => {},
+                _ => panic!("Saving {} should have failed.", name),
+            }
+            assert!(!path.exists());
+        }
+    }
+
+    #[test]
+    fn test_maps_to_closest_color() {
+        let palette = FixedPalette::new(vec![[0, 0, 0], [255, 255, 255], [255, 0, 0]]).unwrap();
+        let img = Image {
+            pixels: vec![[250, 250, 250, 255], [200, 30, 20, 255], [10, 10, 10, 255], [0, 0, 0, 0]],
+            width: 2,
+            height: 2,
+        };
+        let computed = palette.palette(&[img], 256);
+        assert_eq!(computed.colors, vec![0, 0, 0, 255, 255, 255, 255, 0, 0, 0, 0, 0]);
+        assert_eq!(computed.transparency, Some(3));
+        let index_of = &computed.index_of;
+        assert_eq!(index_of(&[250, 250, 250, 255]), 1);
+        assert_eq!(index_of(&[200, 30, 20, 255]), 2);
+        assert_eq!(index_of(&[10, 10, 10, 255]), 0);
+        assert_eq!(index_of(&[255, 255, 255, 0]), 3);
+    }
+}
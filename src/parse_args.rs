@@ -8,7 +8,7 @@ use std::str::FromStr;
 use std;
 
 use self::SourceImages::*;
-use engiffen::Quantizer;
+use engiffen::{Quantizer, Dither, Loop, Matte, PaletteFormat, PaletteMode, MAX_DELAY};
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum SourceImages {
@@ -17,18 +17,47 @@ pub enum SourceImages {
     #[cfg(feature = "globbing")] Glob(String),
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, PartialEq)]
 pub enum Modifier {
     Reverse,
-    Shuffle
+    Shuffle,
+    Dedupe,
+    PingPong,
+    Every(usize),
+    Skip(usize),
+    Take(usize),
+    Fps(f64),
 }
 
+/// Frame durations read from a `--delays` file, in milliseconds. Either one
+/// duration for each frame in order, or durations for specific image paths.
 #[derive(Debug, Eq, PartialEq)]
+pub enum Delays {
+    Sequence(Vec<u32>),
+    Paths(Vec<(PathBuf, u32)>),
+}
+
+#[derive(Debug, PartialEq)]
 pub struct Args {
     pub source: SourceImages,
-    pub fps: usize,
+    pub fps: f64,
     pub out_file: Option<String>,
     pub quantizer: Quantizer,
+    pub dither: Dither,
+    pub dither_strength: f32,
+    pub kmeans: u32,
+    pub colors: u16,
+    pub palette_mode: PaletteMode,
+    pub alpha_threshold: u8,
+    pub matte: Option<Matte>,
+    pub palette_file: Option<String>,
+    pub save_palette: Option<String>,
+    pub delays_file: Option<String>,
+    pub looping: Loop,
+    pub optimize: bool,
+    pub lossy: u32,
+    pub max_size: Option<usize>,
+    pub verbose: bool,
     pub modifiers: Vec<Modifier>,
 }
 
@@ -36,8 +65,12 @@ pub struct Args {
 pub enum ArgsError {
     Parse(getopts::Fail),
     ParseInt(std::num::ParseIntError),
+    ParseFloat(std::num::ParseFloatError),
+    UnknownValue(String, String),
     #[cfg(feature = "globbing")] GlobPattern,
     ImageRange(String),
+    Delays(usize, String),
+    Conflict(String, String),
     DisplayHelp(String),
 }
 
@@ -53,6 +86,12 @@ impl From<std::num::ParseIntError> for ArgsError {
     }
 }
 
+impl From<std::num::ParseFloatError> for ArgsError {
+    fn from(err: std::num::ParseFloatError) -> ArgsError {
+        ArgsError::ParseFloat(err)
+    }
+}
+
 #[cfg(feature = "globbing")]
 impl From<glob::PatternError> for ArgsError {
     fn from(_: glob::PatternError) -> ArgsError {
@@ -65,8 +104,12 @@ impl fmt::Display for ArgsError {
         match *self {
             ArgsError::Parse(ref err) => write!(f, "Options parse error: {}", err),
             ArgsError::ParseInt(_) => write!(f, "Unable to parse argument as an integer"),
+            ArgsError::ParseFloat(_) => write!(f, "Unable to parse argument as a number"),
+            ArgsError::UnknownValue(ref opt, ref value) => write!(f, "Unknown value `{}` for option --{}", value, opt),
             #[cfg(feature = "globbing")] ArgsError::GlobPattern => write!(f, "Unable to parse glob pattern"),
             ArgsError::ImageRange(ref s) => write!(f, "Bad image range: {}", s),
+            ArgsError::Delays(line, ref s) => write!(f, "Bad delay on line {}: {}", line, s),
+            ArgsError::Conflict(ref opt, ref other) => write!(f, "Options --{} and --{} can't be used together", opt, other),
             ArgsError::DisplayHelp(ref msg) => write!(f, "{}", msg),
         }
     }
@@ -77,8 +120,12 @@ impl error::Error for ArgsError {
         match *self {
             ArgsError::Parse(ref err) => err.description(),
             ArgsError::ParseInt(ref err) => err.description(),
+            ArgsError::ParseFloat(_) => "Unable to parse argument as a number",
+            ArgsError::UnknownValue(_, _) => "Unknown option value",
             #[cfg(feature = "globbing")] ArgsError::GlobPattern => "Bad glob pattern",
             ArgsError::ImageRange(_) => "Bad image range",
+            ArgsError::Delays(_, _) => "Bad delay",
+            ArgsError::Conflict(_, _) => "Conflicting options",
             ArgsError::DisplayHelp(_) => "Display help message"
         }
     }
@@ -87,8 +134,12 @@ impl error::Error for ArgsError {
         match *self {
             ArgsError::Parse(ref err) => Some(err),
             ArgsError::ParseInt(ref err) => Some(err),
+            ArgsError::ParseFloat(ref err) => Some(err),
+            ArgsError::UnknownValue(_, _) => None,
             #[cfg(feature = "globbing")] ArgsError::GlobPattern => None,
             ArgsError::ImageRange(_) => None,
+            ArgsError::Delays(_, _) => None,
+            ArgsError::Conflict(_, _) => None,
             ArgsError::DisplayHelp(_) => None,
         }
     }
@@ -99,11 +150,26 @@ pub fn parse_args(args: &[String]) -> Result<Args, ArgsError> {
 
     let mut opts = Options::new();
     opts.optopt("o", "outfile", "engiffen to this filename", "FILE");
-    opts.optopt("f", "framerate", "frames per second", "30");
+    opts.optopt("f", "framerate", "frames per second, can be fractional (e.g. 23.976 or 30000/1001)", "30");
     opts.optopt("s", "sample-rate", "reduces how many pixels are analyzed when generating palette, higher means faster", "2");
-    opts.optopt("q", "quantizer", "pick quantizer algorithm (default: neuquant)", "naive");
+    opts.optopt("q", "quantizer", "pick quantizer algorithm: neuquant, naive, mediancut, octree or wu (default: neuquant)", "naive");
+    opts.optopt("", "dither", "dither pixels onto the palette (floyd-steinberg, atkinson, sierra, bayer2, bayer4, bayer8, blue-noise, none)", "sierra");
+    opts.optopt("", "dither-strength", "how much error dithering spreads, from 0.0 to 1.0 (default: 1.0)", "0.8");
+    opts.optopt("", "colors", "most colors in the palette, from 2 to 256 (default: 256)", "N");
+    opts.optopt("", "palettes", "give frames palettes of their own: global, frame, scene, or auto to only when it looks better (default: global)", "auto");
+    opts.optopt("", "alpha-threshold", "pixels with less alpha than this, from 0 to 255, are transparent and the rest opaque (default: 1)", "128");
+    opts.optopt("", "matte", "blend partly transparent pixels over this background color", "#RRGGBB");
+    opts.optopt("", "kmeans", "refine the palette with up to N rounds of k-means clustering, unless it comes from --palette (default: 0, off)", "N");
+    opts.optopt("", "palette", "map frames onto the colors of a .gpl, .act, .hex or swatch image file instead of computing a palette", "FILE");
+    opts.optopt("", "save-palette", "also save the gif's palette as a .gpl, .act, .hex, .txt or .png swatch image file", "FILE");
+    opts.optopt("", "delays", "read frame durations from a file, one per line as `duration` or `path duration` (e.g. 40, 40ms or 2s)", "FILE");
+    opts.optopt("", "loop", "how many times the gif plays (default: infinite)", "infinite|once|N");
+    opts.optflag("", "optimize", "only write the area of each frame that changed from the previous one");
+    opts.optopt("", "lossy", "let colors shift by up to N to shrink the file, around 10 to 40 works well (default: 0, off)", "N");
+    opts.optopt("", "max-size", "lower colors, lossiness, frame rate and scale as needed for the gif to fit in this many bytes, or KB or MB. Always optimizes, and can't be used with --lossy", "2MB");
     opts.optflag("r", "range", "arguments specify start and end images");
-    opts.optmulti("n", "reorder", "reorder frames before processing", "reverse");
+    opts.optmulti("n", "reorder", "reorder frames before processing (reverse or shuffle), play them forwards then backwards (pingpong), drop frames that repeat the one before them (dedupe), or thin out and trim them (every=N, fps=N, skip=N, take=N)", "reverse");
+    opts.optflag("v", "verbose", "print details like where scenes cut");
     opts.optflag("h", "help", "display this help");
 
     let matches = opts.parse(&args[1..])?;
@@ -120,16 +186,103 @@ pub fn parse_args(args: &[String]) -> Result<Args, ArgsError> {
 
     let quantizer = match matches.opt_str("q").map(|s| s.to_lowercase()) {
         Some(ref s) if s == "naive" => Quantizer::Naive,
+        Some(ref s) if s == "mediancut" || s == "median-cut" => Quantizer::MedianCut,
+        Some(ref s) if s == "octree" => Quantizer::Octree,
+        Some(ref s) if s == "wu" => Quantizer::Wu,
         Some(_) => {
             Quantizer::NeuQuant(sample_rate)
         },
         None => Quantizer::NeuQuant(sample_rate),
     };
 
-    let fps: usize = if let Some(fps_str) = matches.opt_str("f") {
-        usize::from_str(&fps_str)?
+    let dither = match matches.opt_str("dither").map(|s| s.to_lowercase()) {
+        Some(ref s) if s == "floyd-steinberg" || s == "fs" => Dither::FloydSteinberg,
+        Some(ref s) if s == "atkinson" => Dither::Atkinson,
+        Some(ref s) if s == "sierra" => Dither::Sierra,
+        Some(ref s) if s == "bayer2" => Dither::Bayer2,
+        Some(ref s) if s == "bayer4" => Dither::Bayer4,
+        Some(ref s) if s == "bayer8" || s == "bayer" => Dither::Bayer8,
+        Some(ref s) if s == "blue-noise" || s == "bluenoise" => Dither::BlueNoise,
+        Some(ref s) if s == "none" => Dither::None,
+        Some(s) => return Err(ArgsError::UnknownValue("dither".to_string(), s)),
+        None => Dither::None,
+    };
+
+    let dither_strength = match matches.opt_str("dither-strength") {
+        Some(s) => match f32::from_str(&s) {
+            Ok(strength) if (0.0..=1.0).contains(&strength) => strength,
+            _ => return Err(ArgsError::UnknownValue("dither-strength".to_string(), s)),
+        },
+        None => 1.0,
+    };
+
+    let colors = match matches.opt_str("colors") {
+        Some(s) => match u16::from_str(&s) {
+            Ok(colors) if (2..=256).contains(&colors) => colors,
+            _ => return Err(ArgsError::UnknownValue("colors".to_string(), s)),
+        },
+        None => 256,
+    };
+
+    let palette_mode = match matches.opt_str("palettes") {
+        Some(ref s) if s == "global" => PaletteMode::Global,
+        Some(ref s) if s == "frame" || s == "per-frame" => PaletteMode::PerFrame,
+        Some(ref s) if s == "scene" || s == "per-scene" => PaletteMode::PerScene,
+        Some(ref s) if s == "auto" => PaletteMode::Auto,
+        Some(s) => return Err(ArgsError::UnknownValue("palettes".to_string(), s)),
+        None => PaletteMode::Global,
+    };
+
+    let alpha_threshold = if let Some(threshold_str) = matches.opt_str("alpha-threshold") {
+        u8::from_str(&threshold_str)?
     } else {
-        30
+        1
+    };
+
+    let matte = match matches.opt_str("matte") {
+        Some(s) => match parse_color(&s) {
+            Some(color) => Some(Matte::Color(color)),
+            None => return Err(ArgsError::UnknownValue("matte".to_string(), s)),
+        },
+        None => None,
+    };
+
+    let lossy = if let Some(lossy_str) = matches.opt_str("lossy") {
+        u32::from_str(&lossy_str)?
+    } else {
+        0
+    };
+
+    let max_size = match matches.opt_str("max-size") {
+        Some(size_str) => Some(parse_size(&size_str)?),
+        None => None,
+    };
+    // The size search picks the lossy level itself
+    if max_size.is_some() && matches.opt_present("lossy") {
+        return Err(ArgsError::Conflict("max-size".to_string(), "lossy".to_string()));
+    }
+
+    let kmeans = if let Some(kmeans_str) = matches.opt_str("kmeans") {
+        u32::from_str(&kmeans_str)?
+    } else {
+        0
+    };
+
+    let fps = if let Some(fps_str) = matches.opt_str("f") {
+        parse_fps(&fps_str)?
+    } else {
+        30.0
+    };
+
+    let looping = match matches.opt_str("loop").map(|s| s.to_lowercase()) {
+        Some(ref s) if s == "infinite" || s == "forever" => Loop::Infinite,
+        Some(ref s) if s == "once" => Loop::Once,
+        Some(s) => match u16::from_str(&s) {
+            Ok(1) => Loop::Once,
+            Ok(plays) if plays > 1 => Loop::Repeat(plays - 1),
+            _ => return Err(ArgsError::UnknownValue("loop".to_string(), s)),
+        },
+        None => Loop::Infinite,
     };
 
     let mut modifiers = vec![];
@@ -137,10 +290,26 @@ pub fn parse_args(args: &[String]) -> Result<Args, ArgsError> {
         match opt_str.as_str() {
             "reverse" | "rev" => modifiers.push(Modifier::Reverse),
             "shuffle" => modifiers.push(Modifier::Shuffle),
-            m @ _ => eprintln!("Ignoring unknown modifier `{}`", m),
+            "dedupe" => modifiers.push(Modifier::Dedupe),
+            "pingpong" | "boomerang" => modifiers.push(Modifier::PingPong),
+            m => match parse_modifier(m)? {
+                Some(modifier) => modifiers.push(modifier),
+                None => eprintln!("Ignoring unknown modifier `{}`", m),
+            },
         }
     }
 
+    let delays_file = matches.opt_str("delays");
+    let palette_file = matches.opt_str("palette");
+    let save_palette = matches.opt_str("save-palette");
+    if let Some(ref path) = save_palette {
+        if PaletteFormat::from_path(path).is_none() {
+            return Err(ArgsError::UnknownValue("save-palette".to_string(), path.clone()));
+        }
+    }
+    let optimize = matches.opt_present("optimize");
+    let verbose = matches.opt_present("v");
+
     let out_file = matches.opt_str("o").map(|f| f.clone());
     let source = if matches.opt_present("r") {
         if matches.free.len() >= 2 {
@@ -169,14 +338,144 @@ pub fn parse_args(args: &[String]) -> Result<Args, ArgsError> {
     };
 
     Ok(Args {
-        source: source,
-        fps: fps,
-        out_file: out_file,
-        quantizer: quantizer,
-        modifiers: modifiers,
+        source,
+        fps,
+        out_file,
+        quantizer,
+        dither,
+        dither_strength,
+        kmeans,
+        colors,
+        palette_mode,
+        alpha_threshold,
+        matte,
+        palette_file,
+        save_palette,
+        delays_file,
+        looping,
+        optimize,
+        lossy,
+        max_size,
+        verbose,
+        modifiers,
     })
 }
 
+/// Parses a frame rate given either as a number like `30` or `29.97`, or as a
+/// ratio like `30000/1001`.
+fn parse_fps(input: &str) -> Result<f64, ArgsError> {
+    let fps = match input.find('/') {
+        Some(slash) => f64::from_str(&input[..slash])? / f64::from_str(&input[slash + 1..])?,
+        None => f64::from_str(input)?,
+    };
+    if fps.is_finite() && fps > 0.0 {
+        Ok(fps)
+    } else {
+        Err(ArgsError::UnknownValue("framerate".to_string(), input.to_string()))
+    }
+}
+
+/// Parses a modifier that takes a value, like `every=3`, `skip=10`,
+/// `take=120` or `fps=15`. Returns `None` for unknown modifiers.
+fn parse_modifier(input: &str) -> Result<Option<Modifier>, ArgsError> {
+    let invalid = || ArgsError::UnknownValue("reorder".to_string(), input.to_string());
+    let (name, value) = match input.find('=') {
+        Some(eq) => (&input[..eq], &input[eq + 1..]),
+        None => return Ok(None),
+    };
+    let count = |min: usize| match usize::from_str(value) {
+        Ok(n) if n >= min => Ok(n),
+        _ => Err(invalid()),
+    };
+    let modifier = match name {
+        "every" => Modifier::Every(count(1)?),
+        "skip" => Modifier::Skip(count(0)?),
+        "take" => Modifier::Take(count(1)?),
+        "fps" => Modifier::Fps(parse_fps(value).map_err(|_| invalid())?),
+        _ => return Ok(None),
+    };
+    Ok(Some(modifier))
+}
+
+/// Parses a file size in bytes like `500000`, or with a `KB` or `MB` suffix
+/// like `500KB` or `1.5MB`. Suffixes count in thousands, so the size fits
+/// under limits that count in 1024s too.
+fn parse_size(input: &str) -> Result<usize, ArgsError> {
+    let upper = input.trim().to_uppercase();
+    let (number, unit) = if let Some(number) = upper.strip_suffix("MB") {
+        (number, 1_000_000.0)
+    } else if let Some(number) = upper.strip_suffix("KB") {
+        (number, 1_000.0)
+    } else {
+        (upper.strip_suffix('B').unwrap_or(&upper), 1.0)
+    };
+    let size = f64::from_str(number.trim())? * unit;
+    if size.is_finite() && size >= 1.0 {
+        Ok(size as usize)
+    } else {
+        Err(ArgsError::UnknownValue("max-size".to_string(), input.to_string()))
+    }
+}
+
+/// Parses the contents of a `--delays` file. Each line holds either a duration,
+/// or an image path followed by a duration. Durations are in milliseconds,
+/// unless suffixed with `s` for seconds. Blank lines and lines starting with
+/// `#` are skipped.
+pub fn parse_delays(contents: &str) -> Result<Delays, ArgsError> {
+    let mut sequence = vec![];
+    let mut paths = vec![];
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.rsplitn(2, char::is_whitespace);
+        let duration_str = parts.next().unwrap_or("");
+        let duration = parse_duration(duration_str)
+            .ok_or_else(|| ArgsError::Delays(n + 1, format!("can't parse duration `{}`", duration_str)))?;
+        if duration > f64::from(MAX_DELAY) {
+            return Err(ArgsError::Delays(n + 1, format!("duration `{}` is longer than the {} ms a frame can last", duration_str, MAX_DELAY)));
+        }
+        match parts.next() {
+            Some(path) => paths.push((PathBuf::from(path.trim_end()), duration as u32)),
+            None => sequence.push(duration as u32),
+        }
+        if !paths.is_empty() && !sequence.is_empty() {
+            return Err(ArgsError::Delays(n + 1, "can't mix lines with and without image paths".to_string()));
+        }
+    }
+    if paths.is_empty() {
+        Ok(Delays::Sequence(sequence))
+    } else {
+        Ok(Delays::Paths(paths))
+    }
+}
+
+/// Parses a duration into milliseconds, rounded to the nearest one.
+fn parse_duration(input: &str) -> Option<f64> {
+    let ms = if let Some(ms) = input.strip_suffix("ms") {
+        f64::from_str(ms).ok()?
+    } else if let Some(seconds) = input.strip_suffix('s') {
+        f64::from_str(seconds).ok()? * 1000.0
+    } else {
+        f64::from_str(input).ok()?
+    };
+    if ms >= 0.0 && ms.is_finite() {
+        Some(ms.round())
+    } else {
+        None
+    }
+}
+
+fn parse_color(input: &str) -> Option<[u8; 3]> {
+    let hex = input.trim_start_matches('#');
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
 fn path_and_filename(input: &str) -> Result<(PathBuf, PathBuf), ArgsError> {
     let p = Path::new(&input);
     let parent = match p.parent() {
@@ -199,7 +498,7 @@ fn path_and_filename(input: &str) -> Result<(PathBuf, PathBuf), ArgsError> {
 #[cfg(test)]
 #[allow(unused_must_use)]
 mod tests {
-    use super::{parse_args, SourceImages, ArgsError, Args, Quantizer};
+    use super::{parse_args, parse_delays, SourceImages, ArgsError, Args, Delays, Quantizer, Dither, Loop, Matte, Modifier, PaletteMode};
     use std::path::PathBuf;
     use std::str::FromStr;
 
@@ -223,7 +522,23 @@ mod tests {
     fn test_fps() {
         let args = parse_args(&make_args("engiffen -f 45"));
         assert!(args.is_ok());
-        assert_eq!(args.unwrap().fps, 45);
+        assert_eq!(args.unwrap().fps, 45.0);
+    }
+
+    #[test]
+    fn test_fps_fractional() {
+        let args = parse_args(&make_args("engiffen -f 23.976")).unwrap();
+        assert_eq!(args.fps, 23.976);
+        let args = parse_args(&make_args("engiffen -f 30000/1001")).unwrap();
+        assert_eq!(args.fps, 30000.0 / 1001.0);
+    }
+
+    #[test]
+    fn test_fps_invalid() {
+        let args = parse_args(&make_args("engiffen -f 30/0"));
+        assert_err_eq(args, ArgsError::UnknownValue("framerate".to_owned(), "30/0".to_owned()));
+        let args = parse_args(&make_args("engiffen -f -5"));
+        assert_err_eq(args, ArgsError::UnknownValue("framerate".to_owned(), "-5".to_owned()));
     }
 
     #[test]
@@ -231,8 +546,22 @@ mod tests {
         use std::str::FromStr;
 
         let args = parse_args(&make_args("engiffen -f barry"));
-        let parse_error = usize::from_str("barry").err().unwrap();
-        assert_err_eq(args, ArgsError::ParseInt(parse_error));
+        let parse_error = f64::from_str("barry").err().unwrap();
+        assert_err_eq(args, ArgsError::ParseFloat(parse_error));
+    }
+
+    #[test]
+    fn test_quantizer() {
+        let args = parse_args(&make_args("engiffen -q naive"));
+        assert_eq!(args.unwrap().quantizer, Quantizer::Naive);
+        let args = parse_args(&make_args("engiffen -q mediancut"));
+        assert_eq!(args.unwrap().quantizer, Quantizer::MedianCut);
+        let args = parse_args(&make_args("engiffen -q Median-Cut"));
+        assert_eq!(args.unwrap().quantizer, Quantizer::MedianCut);
+        let args = parse_args(&make_args("engiffen -q octree"));
+        assert_eq!(args.unwrap().quantizer, Quantizer::Octree);
+        let args = parse_args(&make_args("engiffen -q wu"));
+        assert_eq!(args.unwrap().quantizer, Quantizer::Wu);
     }
 
     #[test]
@@ -249,6 +578,220 @@ mod tests {
         assert_err_eq(args, ArgsError::ParseInt(parse_error));
     }
 
+    #[test]
+    fn test_dither() {
+        let args = parse_args(&make_args("engiffen --dither atkinson --dither-strength 0.5")).unwrap();
+        assert_eq!(args.dither, Dither::Atkinson);
+        assert_eq!(args.dither_strength, 0.5);
+        for strength in &["1.5", "-0.1", "NaN", "inf", "lots"] {
+            let args = parse_args(&make_args(&format!("engiffen --dither-strength {}", strength)));
+            assert_err_eq(args, ArgsError::UnknownValue("dither-strength".to_owned(), strength.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_dither_ordered() {
+        let args = parse_args(&make_args("engiffen --dither blue-noise")).unwrap();
+        assert_eq!(args.dither, Dither::BlueNoise);
+        let args = parse_args(&make_args("engiffen --dither bayer4")).unwrap();
+        assert_eq!(args.dither, Dither::Bayer4);
+    }
+
+    #[test]
+    fn test_dither_default() {
+        let args = parse_args(&make_args("engiffen")).unwrap();
+        assert_eq!(args.dither, Dither::None);
+        assert_eq!(args.dither_strength, 1.0);
+    }
+
+    #[test]
+    fn test_kmeans() {
+        let args = parse_args(&make_args("engiffen --kmeans 8")).unwrap();
+        assert_eq!(args.kmeans, 8);
+        let args = parse_args(&make_args("engiffen")).unwrap();
+        assert_eq!(args.kmeans, 0);
+        let args = parse_args(&make_args("engiffen --kmeans lots"));
+        let parse_error = u32::from_str("lots").err().unwrap();
+        assert_err_eq(args, ArgsError::ParseInt(parse_error));
+    }
+
+    #[test]
+    fn test_dither_unknown() {
+        let args = parse_args(&make_args("engiffen --dither wobbly"));
+        assert_err_eq(args, ArgsError::UnknownValue("dither".to_owned(), "wobbly".to_owned()));
+    }
+
+    #[test]
+    fn test_colors() {
+        let args = parse_args(&make_args("engiffen --colors 16")).unwrap();
+        assert_eq!(args.colors, 16);
+        let args = parse_args(&make_args("engiffen")).unwrap();
+        assert_eq!(args.colors, 256);
+        let args = parse_args(&make_args("engiffen --colors 1"));
+        assert_err_eq(args, ArgsError::UnknownValue("colors".to_owned(), "1".to_owned()));
+        let args = parse_args(&make_args("engiffen --colors 257"));
+        assert_err_eq(args, ArgsError::UnknownValue("colors".to_owned(), "257".to_owned()));
+    }
+
+    #[test]
+    fn test_palette_mode() {
+        let args = parse_args(&make_args("engiffen --palettes auto")).unwrap();
+        assert_eq!(args.palette_mode, PaletteMode::Auto);
+        let args = parse_args(&make_args("engiffen --palettes frame")).unwrap();
+        assert_eq!(args.palette_mode, PaletteMode::PerFrame);
+        let args = parse_args(&make_args("engiffen --palettes scene")).unwrap();
+        assert_eq!(args.palette_mode, PaletteMode::PerScene);
+        let args = parse_args(&make_args("engiffen")).unwrap();
+        assert_eq!(args.palette_mode, PaletteMode::Global);
+        let args = parse_args(&make_args("engiffen --palettes some"));
+        assert_err_eq(args, ArgsError::UnknownValue("palettes".to_owned(), "some".to_owned()));
+    }
+
+    #[test]
+    fn test_alpha_threshold() {
+        let args = parse_args(&make_args("engiffen --alpha-threshold 128")).unwrap();
+        assert_eq!(args.alpha_threshold, 128);
+        let args = parse_args(&make_args("engiffen")).unwrap();
+        assert_eq!(args.alpha_threshold, 1);
+        assert!(parse_args(&make_args("engiffen --alpha-threshold 256")).is_err());
+    }
+
+    #[test]
+    fn test_lossy() {
+        let args = parse_args(&make_args("engiffen --lossy 20")).unwrap();
+        assert_eq!(args.lossy, 20);
+        let args = parse_args(&make_args("engiffen")).unwrap();
+        assert_eq!(args.lossy, 0);
+        assert!(parse_args(&make_args("engiffen --lossy -5")).is_err());
+    }
+
+    #[test]
+    fn test_max_size() {
+        let args = parse_args(&make_args("engiffen --max-size 2MB")).unwrap();
+        assert_eq!(args.max_size, Some(2_000_000));
+        let args = parse_args(&make_args("engiffen --max-size 1.5mb")).unwrap();
+        assert_eq!(args.max_size, Some(1_500_000));
+        let args = parse_args(&make_args("engiffen --max-size 750KB")).unwrap();
+        assert_eq!(args.max_size, Some(750_000));
+        let args = parse_args(&make_args("engiffen --max-size 4096")).unwrap();
+        assert_eq!(args.max_size, Some(4096));
+        let args = parse_args(&make_args("engiffen")).unwrap();
+        assert_eq!(args.max_size, None);
+        let args = parse_args(&make_args("engiffen --max-size 0MB"));
+        assert_err_eq(args, ArgsError::UnknownValue("max-size".to_owned(), "0MB".to_owned()));
+        let args = parse_args(&make_args("engiffen --max-size 2MB --lossy 20"));
+        assert_err_eq(args, ArgsError::Conflict("max-size".to_owned(), "lossy".to_owned()));
+        let args = parse_args(&make_args("engiffen --max-size 2MB --optimize")).unwrap();
+        assert!(args.optimize);
+        assert!(parse_args(&make_args("engiffen --max-size 2GB")).is_err());
+    }
+
+    #[test]
+    fn test_modifiers() {
+        let args = parse_args(&make_args("engiffen -n reverse -n dedupe")).unwrap();
+        assert_eq!(args.modifiers, vec![Modifier::Reverse, Modifier::Dedupe]);
+        let args = parse_args(&make_args("engiffen -n pingpong -n boomerang")).unwrap();
+        assert_eq!(args.modifiers, vec![Modifier::PingPong, Modifier::PingPong]);
+        let args = parse_args(&make_args("engiffen -n skip=10 -n take=120 -n every=3 -n fps=30000/1001")).unwrap();
+        assert_eq!(args.modifiers, vec![Modifier::Skip(10), Modifier::Take(120), Modifier::Every(3), Modifier::Fps(30000.0 / 1001.0)]);
+        let args = parse_args(&make_args("engiffen -n sideways -n slant=3")).unwrap();
+        assert_eq!(args.modifiers, vec![]);
+        for bad in &["every=0", "take=0", "skip=-1", "every=two", "fps=0"] {
+            let args = parse_args(&make_args(&format!("engiffen -n {}", bad)));
+            assert_err_eq(args, ArgsError::UnknownValue("reorder".to_owned(), bad.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_matte() {
+        let args = parse_args(&make_args("engiffen --matte #FF8000")).unwrap();
+        assert_eq!(args.matte, Some(Matte::Color([255, 128, 0])));
+        let args = parse_args(&make_args("engiffen --matte 00aaff")).unwrap();
+        assert_eq!(args.matte, Some(Matte::Color([0, 170, 255])));
+        let args = parse_args(&make_args("engiffen")).unwrap();
+        assert_eq!(args.matte, None);
+        let args = parse_args(&make_args("engiffen --matte white"));
+        assert_err_eq(args, ArgsError::UnknownValue("matte".to_owned(), "white".to_owned()));
+    }
+
+    #[test]
+    fn test_palette_file() {
+        let args = parse_args(&make_args("engiffen --palette brand.gpl")).unwrap();
+        assert_eq!(args.palette_file, Some("brand.gpl".to_owned()));
+        let args = parse_args(&make_args("engiffen")).unwrap();
+        assert_eq!(args.palette_file, None);
+    }
+
+    #[test]
+    fn test_save_palette() {
+        let args = parse_args(&make_args("engiffen --save-palette colors.act")).unwrap();
+        assert_eq!(args.save_palette, Some("colors.act".to_owned()));
+        let args = parse_args(&make_args("engiffen")).unwrap();
+        assert_eq!(args.save_palette, None);
+        assert_eq!(parse_args(&make_args("engiffen --save-palette colors.gif")).err().unwrap(),
+            ArgsError::UnknownValue("save-palette".to_string(), "colors.gif".to_string()));
+    }
+
+    #[test]
+    fn test_delays_file() {
+        let args = parse_args(&make_args("engiffen --delays timing.txt")).unwrap();
+        assert_eq!(args.delays_file, Some("timing.txt".to_owned()));
+    }
+
+    #[test]
+    fn test_parse_delay_sequence() {
+        let delays = parse_delays("2s\n\n# the rest at 24fps\n42\n41.7ms\n1.5s\n").unwrap();
+        assert_eq!(delays, Delays::Sequence(vec![2000, 42, 42, 1500]));
+    }
+
+    #[test]
+    fn test_parse_delay_paths() {
+        let delays = parse_delays("title card.png 2s\nframe01.png\t40\n").unwrap();
+        assert_eq!(delays, Delays::Paths(vec![
+            (PathBuf::from("title card.png"), 2000),
+            (PathBuf::from("frame01.png"), 40),
+        ]));
+    }
+
+    #[test]
+    fn test_parse_delay_errors() {
+        assert_eq!(parse_delays("40\nbarry\n").err().unwrap(), ArgsError::Delays(2, "can't parse duration `barry`".to_string()));
+        assert_eq!(parse_delays("40\nframe01.png 40\n").err().unwrap(), ArgsError::Delays(2, "can't mix lines with and without image paths".to_string()));
+        assert_eq!(parse_delays("-5\n").err().unwrap(), ArgsError::Delays(1, "can't parse duration `-5`".to_string()));
+        assert_eq!(parse_delays("655350\n").unwrap(), Delays::Sequence(vec![655350]));
+        assert_eq!(parse_delays("40\n656s\n").err().unwrap(), ArgsError::Delays(2, "duration `656s` is longer than the 655350 ms a frame can last".to_string()));
+    }
+
+    #[test]
+    fn test_loop() {
+        assert_eq!(parse_args(&make_args("engiffen")).unwrap().looping, Loop::Infinite);
+        assert_eq!(parse_args(&make_args("engiffen --loop infinite")).unwrap().looping, Loop::Infinite);
+        assert_eq!(parse_args(&make_args("engiffen --loop once")).unwrap().looping, Loop::Once);
+        assert_eq!(parse_args(&make_args("engiffen --loop 1")).unwrap().looping, Loop::Once);
+        assert_eq!(parse_args(&make_args("engiffen --loop 3")).unwrap().looping, Loop::Repeat(2));
+    }
+
+    #[test]
+    fn test_loop_invalid() {
+        let args = parse_args(&make_args("engiffen --loop 0"));
+        assert_err_eq(args, ArgsError::UnknownValue("loop".to_owned(), "0".to_owned()));
+        let args = parse_args(&make_args("engiffen --loop sometimes"));
+        assert_err_eq(args, ArgsError::UnknownValue("loop".to_owned(), "sometimes".to_owned()));
+    }
+
+    #[test]
+    fn test_optimize() {
+        assert!(!parse_args(&make_args("engiffen")).unwrap().optimize);
+        assert!(parse_args(&make_args("engiffen --optimize")).unwrap().optimize);
+    }
+
+    #[test]
+    fn test_verbose() {
+        assert!(!parse_args(&make_args("engiffen")).unwrap().verbose);
+        assert!(parse_args(&make_args("engiffen -v")).unwrap().verbose);
+        assert!(parse_args(&make_args("engiffen --verbose")).unwrap().verbose);
+    }
+
     #[test]
     fn test_file_list() {
         let args = parse_args(&make_args("engiffen this.jpg that.jpg other.jpg"));
@@ -0,0 +1,104 @@
+//! Reservoir sampling.
+//!
+//! Keeps a sample of at most a fixed number of items from a stream of any
+//! length, where every item seen so far is equally likely to be in it. Until
+//! the reservoir fills up, it simply holds every item in order.
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+/// Seed for the random choices, so the same stream always gives the same
+/// sample.
+const SEED: [u32; 4] = [0x193a_6754, 0xa8a7_d469, 0x9783_0e05, 0x113b_a7bb];
+
+pub struct Reservoir<T> {
+    items: Vec<T>,
+    seen: u64,
+    capacity: usize,
+    rng: XorShiftRng,
+}
+
+impl<T> Reservoir<T> {
+    pub fn new(capacity: usize) -> Reservoir<T> {
+        Reservoir {
+            items: Vec::new(),
+            seen: 0,
+            capacity: capacity.max(1),
+            rng: XorShiftRng::from_seed(SEED),
+        }
+    }
+
+    pub fn add(&mut self, item: T) {
+        self.seen += 1;
+        if self.items.len() < self.capacity {
+            self.items.push(item);
+        } else {
+            let n = self.rng.gen_range(0, self.seen);
+            if n < self.capacity as u64 {
+                self.items[n as usize] = item;
+            }
+        }
+    }
+
+    /// Combines the samples of two streams into a sample of both, with each
+    /// stream's share of it in proportion to how many items it saw. If both
+    /// still hold every item they saw and there's room for all of them,
+    /// they're kept in order, `other`'s after this one's.
+    pub fn merge(mut self, mut other: Reservoir<T>) -> Reservoir<T> {
+        let seen = self.seen + other.seen;
+        if self.items.len() + other.items.len() <= self.capacity && seen == (self.items.len() + other.items.len()) as u64 {
+            self.items.append(&mut other.items);
+            self.seen = seen;
+            return self;
+        }
+        let share = (self.capacity as f64 * self.seen as f64 / seen as f64).round() as usize;
+        let kept = share.min(self.items.len()).max(self.capacity.saturating_sub(other.items.len()));
+        self.rng.shuffle(&mut self.items);
+        self.rng.shuffle(&mut other.items);
+        self.items.truncate(kept);
+        other.items.truncate(self.capacity - self.items.len());
+        self.items.append(&mut other.items);
+        self.seen = seen;
+        self
+    }
+
+    pub fn items(&self) -> &[T] {
+        &self.items
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Reservoir;
+
+    #[test]
+    fn test_keeps_everything_that_fits() {
+        let mut left = Reservoir::new(10);
+        let mut right = Reservoir::new(10);
+        for n in 0..4 {
+            left.add(n);
+            right.add(n + 4);
+        }
+        assert_eq!(left.merge(right).items(), &[0, 1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn test_sample_is_bounded_and_spread() {
+        let mut reservoir = Reservoir::new(1000);
+        for n in 0..100_000 {
+            reservoir.add(n);
+        }
+        assert_eq!(reservoir.items().len(), 1000);
+        let late = reservoir.items().iter().filter(|&&n| n >= 50_000).count();
+        assert!(late > 400 && late < 600);
+
+        // Merged samples are shared by how much of the stream each saw
+        let mut small = Reservoir::new(1000);
+        for n in 0..25_000 {
+            small.add(n + 100_000);
+        }
+        let merged = reservoir.merge(small);
+        assert_eq!(merged.items().len(), 1000);
+        let from_small = merged.items().iter().filter(|&&n| n >= 100_000).count();
+        assert_eq!(from_small, 200);
+    }
+}
@@ -0,0 +1,120 @@
+//! Scene cut detection.
+//!
+//! Each frame's pixels are counted into a coarse color histogram. Where the
+//! histograms of two consecutive frames differ by more than a threshold, the
+//! second frame starts a new scene.
+
+use std::ops::Range;
+use rayon::prelude::*;
+use super::source::Source;
+use super::Image;
+
+/// Buckets for each of red, green and blue.
+const BUCKETS: usize = 8;
+
+/// Fraction of pixels that have to change buckets between two frames for the
+/// second to start a new scene.
+const CUT_THRESHOLD: f32 = 0.5;
+
+/// Fraction of the frame's pixels in each color bucket, followed by the
+/// fraction that are fully transparent.
+fn histogram(img: &Image) -> Vec<f32> {
+    let mut counts = vec![0usize; BUCKETS * BUCKETS * BUCKETS + 1];
+    for px in &img.pixels {
+        let bucket = if px[3] == 0 {
+            BUCKETS * BUCKETS * BUCKETS
+        } else {
+            let (r, g, b) = (px[0] as usize * BUCKETS / 256, px[1] as usize * BUCKETS / 256, px[2] as usize * BUCKETS / 256);
+            (r * BUCKETS + g) * BUCKETS + b
+        };
+        counts[bucket] += 1;
+    }
+    let total = img.pixels.len().max(1) as f32;
+    counts.into_iter().map(|count| count as f32 / total).collect()
+}
+
+/// Fraction of pixels that would have to move to another bucket to turn one
+/// histogram into the other, from 0.0 for the same colors to 1.0 for none in
+/// common.
+fn difference(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(a, b)| (a - b).abs()).sum::<f32>() / 2.0
+}
+
+/// Splits a sequence of images into scenes, by comparing the color
+/// histograms of consecutive images. Returns the range of frame indices in
+/// each scene, in order. There are no scenes if there are no images.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use engiffen::{load_images, detect_scenes};
+/// let images = load_images(&["intro.png", "intro2.png", "outro.png"]);
+/// for scene in detect_scenes(&images) {
+///     println!("Scene from frame {} to {}", scene.start, scene.end - 1);
+/// }
+/// ```
+pub fn detect_scenes(imgs: &[Image]) -> Vec<Range<usize>> {
+    split(imgs)
+}
+
+/// Scenes of any sequence of frames, like `detect_scenes`. The frames are
+/// read one at a time.
+pub fn split<S: Source + ?Sized>(imgs: &S) -> Vec<Range<usize>> {
+    let histograms: Vec<Vec<f32>> = (0..imgs.count()).into_par_iter().map(|n| histogram(&imgs.frame(n))).collect();
+    let mut scenes = Vec::new();
+    let mut start = 0;
+    for (n, pair) in histograms.windows(2).enumerate() {
+        if difference(&pair[0], &pair[1]) > CUT_THRESHOLD {
+            scenes.push(start..n + 1);
+            start = n + 1;
+        }
+    }
+    if !histograms.is_empty() {
+        scenes.push(start..histograms.len());
+    }
+    scenes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{detect_scenes, difference, histogram};
+    use super::super::Image;
+
+    fn solid(color: [u8; 4]) -> Image {
+        Image { pixels: vec![color; 16], width: 4, height: 4 }
+    }
+
+    #[test]
+    fn test_difference() {
+        let red = histogram(&solid([255, 0, 0, 255]));
+        let dark_red = histogram(&solid([250, 10, 5, 255]));
+        let blue = histogram(&solid([0, 0, 255, 255]));
+        assert_eq!(difference(&red, &dark_red), 0.0);
+        assert_eq!(difference(&red, &blue), 1.0);
+        assert_eq!(difference(&red, &histogram(&solid([255, 0, 0, 0]))), 1.0);
+    }
+
+    #[test]
+    fn test_detect_scenes() {
+        let imgs = vec![
+            solid([255, 0, 0, 255]),
+            solid([250, 10, 0, 255]),
+            solid([0, 0, 255, 255]),
+            solid([0, 10, 250, 255]),
+            solid([0, 10, 250, 255]),
+            solid([0, 255, 0, 255]),
+        ];
+        assert_eq!(detect_scenes(&imgs), vec![0..2, 2..5, 5..6]);
+        assert_eq!(detect_scenes(&imgs[..2]), vec![0..2]);
+        assert_eq!(detect_scenes(&[]), vec![]);
+    }
+
+    #[test]
+    fn test_small_changes_stay_in_scene() {
+        let mut moved = solid([255, 0, 0, 255]);
+        for px in moved.pixels.iter_mut().take(4) {
+            *px = [0, 0, 255, 255];
+        }
+        assert_eq!(detect_scenes(&[solid([255, 0, 0, 255]), moved]), vec![0..2]);
+    }
+}
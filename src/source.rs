@@ -0,0 +1,102 @@
+//! Frames for compiling a Gif, read one at a time.
+//!
+//! Frames can be borrowed as they are, or packed into compressed PNG data as
+//! they arrive and unpacked whenever they're read, so a long stream of them
+//! never has to be held at full size all at once.
+
+use std::borrow::Cow;
+use image::{self, ColorType, ImageFormat};
+use image::png::PNGEncoder;
+use super::{Error, Image};
+
+/// A sequence of frames with the same dimensions.
+pub trait Source: Sync {
+    /// Number of frames.
+    fn count(&self) -> usize;
+
+    /// Width and height shared by every frame.
+    fn dimensions(&self) -> (u32, u32);
+
+    /// The `n`th frame.
+    fn frame(&self, n: usize) -> Cow<'_, Image>;
+}
+
+impl Source for [Image] {
+    fn count(&self) -> usize {
+        self.len()
+    }
+
+    fn dimensions(&self) -> (u32, u32) {
+        self.first().map_or((0, 0), |img| (img.width, img.height))
+    }
+
+    fn frame(&self, n: usize) -> Cow<'_, Image> {
+        Cow::Borrowed(&self[n])
+    }
+}
+
+/// A frame compressed without loss, which takes far less memory than the
+/// frame itself unless its pixels are noisy.
+pub struct Packed {
+    png: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+impl Packed {
+    pub fn new(img: &Image) -> Result<Packed, Error> {
+        let bytes: Vec<u8> = img.pixels.iter().flat_map(|px| px.iter().cloned()).collect();
+        let mut png = Vec::new();
+        PNGEncoder::new(&mut png).encode(&bytes, img.width, img.height, ColorType::RGBA(8))?;
+        Ok(Packed {
+            png,
+            width: img.width,
+            height: img.height,
+        })
+    }
+
+    pub fn unpack(&self) -> Image {
+        let img = image::load_from_memory_with_format(&self.png, ImageFormat::PNG)
+            .expect("Packed frame could not be decoded.");
+        Image {
+            pixels: img.to_rgba().into_raw().chunks(4).map(|px| [px[0], px[1], px[2], px[3]]).collect(),
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+impl Source for [Packed] {
+    fn count(&self) -> usize {
+        self.len()
+    }
+
+    fn dimensions(&self) -> (u32, u32) {
+        self.first().map_or((0, 0), |packed| (packed.width, packed.height))
+    }
+
+    fn frame(&self, n: usize) -> Cow<'_, Image> {
+        Cow::Owned(self[n].unpack())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Packed, Source};
+    use super::super::Image;
+
+    #[test]
+    fn test_packed_round_trip() {
+        let pixels = (0..12 * 5).map(|n| [n as u8, (n * 7) as u8, 255 - n as u8, (n * 40) as u8]).collect();
+        let img = Image { pixels, width: 12, height: 5 };
+        let packed = [Packed::new(&img).unwrap()];
+        assert_eq!((packed.count(), packed.dimensions()), (1, (12, 5)));
+        // Transparent pixels keep their colors
+        assert_eq!(*packed.frame(0), img);
+
+        let flat = Image { pixels: vec![[40, 80, 120, 255]; 64 * 64], width: 64, height: 64 };
+        let packed = Packed::new(&flat).unwrap();
+        assert!(packed.png.len() < flat.pixels.len() / 10);
+        assert_eq!(packed.unpack(), flat);
+    }
+}
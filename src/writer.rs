@@ -0,0 +1,91 @@
+//! Writes Gifs block by block, with frames compressed by the crate's own LZW
+//! encoder instead of the gif crate's, so they can be compressed lossily.
+
+use std::io::{self, Write};
+use super::frames::Frames;
+use super::{centiseconds, lzw, Error, Gif, Loop};
+
+fn write_u16<W: Write>(out: &mut W, value: u16) -> io::Result<()> {
+    out.write_all(&[value as u8, (value >> 8) as u8])
+}
+
+/// The palette padded to the power of two number of colors a color table
+/// holds, along with the size field for the table.
+fn color_table(palette: &[u8]) -> (Vec<u8>, u8) {
+    let len = (palette.len() / 3).clamp(2, 256).next_power_of_two();
+    let mut table = palette[..palette.len().min(len * 3)].to_vec();
+    table.resize(len * 3, 0);
+    (table, len.trailing_zeros() as u8 - 1)
+}
+
+/// Smallest LZW code size that covers every index of the color table and
+/// the frame.
+fn min_code_size(table_len: usize, indices: &[u8]) -> u8 {
+    let symbols = indices.iter().map(|&idx| idx as usize + 1).max().unwrap_or(0).max(table_len);
+    let bits = (0usize.leading_zeros() - (symbols - 1).leading_zeros()) as u8;
+    bits.max(2)
+}
+
+/// Writes the Gif, letting pixels shift by up to `lossy` in RGB space to
+/// make the image data compress better.
+pub fn write<W: Write>(gif: &Gif, out: &mut W, lossy: u32) -> Result<(), Error> {
+    gif.check_delays()?;
+    let (global, global_size) = color_table(&gif.palette);
+    out.write_all(b"GIF89a")?;
+    write_u16(out, gif.width)?;
+    write_u16(out, gif.height)?;
+    out.write_all(&[0x80 | global_size << 4 | global_size, 0, 0])?;
+    out.write_all(&global)?;
+
+    let repeat = match gif.looping {
+        Loop::Infinite => Some(0),
+        Loop::Repeat(0) | Loop::Once => None,
+        Loop::Repeat(count) => Some(count),
+    };
+    if let Some(count) = repeat {
+        out.write_all(&[0x21, 0xFF, 11])?;
+        out.write_all(b"NETSCAPE2.0")?;
+        out.write_all(&[3, 1])?;
+        write_u16(out, count)?;
+        out.write_all(&[0])?;
+    }
+
+    let mut table = lzw::Table::new();
+    for (frame, &delay) in Frames::new(gif).zip(gif.delays.iter()) {
+        // Graphic control extension
+        let flags = (frame.dispose as u8) << 2 | frame.transparent.is_some() as u8;
+        out.write_all(&[0x21, 0xF9, 4, flags])?;
+        write_u16(out, centiseconds(delay))?;
+        out.write_all(&[frame.transparent.unwrap_or(0), 0])?;
+
+        // Image descriptor, followed by the local color table if there is one
+        out.write_all(&[0x2C])?;
+        write_u16(out, frame.left)?;
+        write_u16(out, frame.top)?;
+        write_u16(out, frame.width)?;
+        write_u16(out, frame.height)?;
+        let (palette, table_len): (&[u8], usize) = match frame.palette {
+            Some(ref local) => {
+                let (table, size) = color_table(local);
+                out.write_all(&[0x80 | size])?;
+                out.write_all(&table)?;
+                (local, table.len() / 3)
+            },
+            None => {
+                out.write_all(&[0])?;
+                (&gif.palette, global.len() / 3)
+            },
+        };
+
+        let code_size = min_code_size(table_len, &frame.buffer);
+        let data = lzw::compress(&mut table, &frame.buffer, code_size, palette, frame.transparent, lossy);
+        out.write_all(&[code_size])?;
+        for block in data.chunks(0xFF) {
+            out.write_all(&[block.len() as u8])?;
+            out.write_all(block)?;
+        }
+        out.write_all(&[0])?;
+    }
+    out.write_all(&[0x3B])?;
+    Ok(())
+}
@@ -0,0 +1,394 @@
+//! Xiaolin Wu's color quantizer.
+//!
+//! Colors are counted in a histogram of 32x32x32 cells, along with the sum
+//! of each channel and of the squared channels in every cell. Summing those
+//! moments cumulatively lets the weight, mean and variance of any box of
+//! cells be read off with a handful of lookups. Starting from one box that
+//! holds every cell, the box with the largest variance is repeatedly cut in
+//! two, at the plane that leaves the least variance in the halves, until
+//! there is one box per palette color.
+
+use super::Rgb;
+
+/// Cells along each side of the histogram, plus a leading row of zeroes so
+/// that cumulative moments can be looked up without bounds checks.
+const SIDE: usize = 33;
+
+fn at(r: usize, g: usize, b: usize) -> usize {
+    (r * SIDE + g) * SIDE + b
+}
+
+fn cell(color: &Rgb) -> usize {
+    at((color[0] >> 3) as usize + 1, (color[1] >> 3) as usize + 1, (color[2] >> 3) as usize + 1)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Channel {
+    Red,
+    Green,
+    Blue,
+}
+
+/// A box of histogram cells, exclusive of its lower corner and inclusive of
+/// its upper corner.
+#[derive(Debug, Copy, Clone)]
+struct Cube {
+    r0: usize,
+    r1: usize,
+    g0: usize,
+    g1: usize,
+    b0: usize,
+    b1: usize,
+    cells: usize,
+}
+
+impl Cube {
+    fn lower(&self, channel: Channel) -> usize {
+        match channel {
+            Channel::Red => self.r0,
+            Channel::Green => self.g0,
+            Channel::Blue => self.b0,
+        }
+    }
+
+    fn upper(&self, channel: Channel) -> usize {
+        match channel {
+            Channel::Red => self.r1,
+            Channel::Green => self.g1,
+            Channel::Blue => self.b1,
+        }
+    }
+
+    /// Sum of the moment over every cell in the cube.
+    fn volume<T: Moment>(&self, m: &[T]) -> T {
+        m[at(self.r1, self.g1, self.b1)]
+            .sub(m[at(self.r1, self.g1, self.b0)])
+            .sub(m[at(self.r1, self.g0, self.b1)])
+            .add(m[at(self.r1, self.g0, self.b0)])
+            .sub(m[at(self.r0, self.g1, self.b1)])
+            .add(m[at(self.r0, self.g1, self.b0)])
+            .add(m[at(self.r0, self.g0, self.b1)])
+            .sub(m[at(self.r0, self.g0, self.b0)])
+    }
+
+    /// Part of the volume that doesn't depend on where the cube is cut along
+    /// `channel`, with the sign it has in the volume of the lower half.
+    fn bottom(&self, channel: Channel, m: &[i64]) -> i64 {
+        match channel {
+            Channel::Red => -m[at(self.r0, self.g1, self.b1)] + m[at(self.r0, self.g1, self.b0)]
+                + m[at(self.r0, self.g0, self.b1)] - m[at(self.r0, self.g0, self.b0)],
+            Channel::Green => -m[at(self.r1, self.g0, self.b1)] + m[at(self.r1, self.g0, self.b0)]
+                + m[at(self.r0, self.g0, self.b1)] - m[at(self.r0, self.g0, self.b0)],
+            Channel::Blue => -m[at(self.r1, self.g1, self.b0)] + m[at(self.r1, self.g0, self.b0)]
+                + m[at(self.r0, self.g1, self.b0)] - m[at(self.r0, self.g0, self.b0)],
+        }
+    }
+
+    /// Part of the volume that depends on cutting at `position` along
+    /// `channel`.
+    fn top(&self, channel: Channel, position: usize, m: &[i64]) -> i64 {
+        match channel {
+            Channel::Red => m[at(position, self.g1, self.b1)] - m[at(position, self.g1, self.b0)]
+                - m[at(position, self.g0, self.b1)] + m[at(position, self.g0, self.b0)],
+            Channel::Green => m[at(self.r1, position, self.b1)] - m[at(self.r1, position, self.b0)]
+                - m[at(self.r0, position, self.b1)] + m[at(self.r0, position, self.b0)],
+            Channel::Blue => m[at(self.r1, self.g1, position)] - m[at(self.r1, self.g0, position)]
+                - m[at(self.r0, self.g1, position)] + m[at(self.r0, self.g0, position)],
+        }
+    }
+}
+
+trait Moment: Copy {
+    fn add(self, other: Self) -> Self;
+    fn sub(self, other: Self) -> Self;
+}
+
+impl Moment for i64 {
+    fn add(self, other: i64) -> i64 { self + other }
+    fn sub(self, other: i64) -> i64 { self - other }
+}
+
+impl Moment for f64 {
+    fn add(self, other: f64) -> f64 { self + other }
+    fn sub(self, other: f64) -> f64 { self - other }
+}
+
+/// Color moments of every histogram cell.
+#[derive(Clone)]
+pub struct Histogram {
+    weights: Vec<i64>,
+    reds: Vec<i64>,
+    greens: Vec<i64>,
+    blues: Vec<i64>,
+    squares: Vec<f64>,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        let len = SIDE * SIDE * SIDE;
+        Histogram {
+            weights: vec![0; len],
+            reds: vec![0; len],
+            greens: vec![0; len],
+            blues: vec![0; len],
+            squares: vec![0.0; len],
+        }
+    }
+
+    /// Adds one pixel of `color`.
+    pub fn add(&mut self, color: Rgb) {
+        let i = cell(&color);
+        let (r, g, b) = (color[0] as i64, color[1] as i64, color[2] as i64);
+        self.weights[i] += 1;
+        self.reds[i] += r;
+        self.greens[i] += g;
+        self.blues[i] += b;
+        self.squares[i] += (r * r + g * g + b * b) as f64;
+    }
+
+    /// Adds every pixel from another histogram.
+    pub fn merge(&mut self, other: &Histogram) {
+        for i in 0..self.weights.len() {
+            self.weights[i] += other.weights[i];
+            self.reds[i] += other.reds[i];
+            self.greens[i] += other.greens[i];
+            self.blues[i] += other.blues[i];
+            self.squares[i] += other.squares[i];
+        }
+    }
+
+    /// Turns the moments of each cell into the sum of the moments of every
+    /// cell at or below it on all three channels.
+    fn accumulate(&mut self) {
+        for r in 1..SIDE {
+            let mut area = [0i64; SIDE];
+            let mut area_r = [0i64; SIDE];
+            let mut area_g = [0i64; SIDE];
+            let mut area_b = [0i64; SIDE];
+            let mut area_2 = [0f64; SIDE];
+            for g in 1..SIDE {
+                let (mut line, mut line_r, mut line_g, mut line_b, mut line_2) = (0i64, 0i64, 0i64, 0i64, 0f64);
+                for b in 1..SIDE {
+                    let i = at(r, g, b);
+                    let below = at(r - 1, g, b);
+                    line += self.weights[i];
+                    line_r += self.reds[i];
+                    line_g += self.greens[i];
+                    line_b += self.blues[i];
+                    line_2 += self.squares[i];
+                    area[b] += line;
+                    area_r[b] += line_r;
+                    area_g[b] += line_g;
+                    area_b[b] += line_b;
+                    area_2[b] += line_2;
+                    self.weights[i] = self.weights[below] + area[b];
+                    self.reds[i] = self.reds[below] + area_r[b];
+                    self.greens[i] = self.greens[below] + area_g[b];
+                    self.blues[i] = self.blues[below] + area_b[b];
+                    self.squares[i] = self.squares[below] + area_2[b];
+                }
+            }
+        }
+    }
+
+    /// Weighted variance of the colors in the cube.
+    fn variance(&self, cube: &Cube) -> f64 {
+        let r = cube.volume(&self.reds) as f64;
+        let g = cube.volume(&self.greens) as f64;
+        let b = cube.volume(&self.blues) as f64;
+        let weight = cube.volume(&self.weights) as f64;
+        if weight == 0.0 {
+            return 0.0;
+        }
+        cube.volume(&self.squares) - (r * r + g * g + b * b) / weight
+    }
+
+    /// Finds where to cut the cube along `channel` so that the halves have
+    /// the least variance between them. Returns how good the cut is, which
+    /// is larger for better cuts, and where to make it.
+    fn maximize(&self, cube: &Cube, channel: Channel, whole: [i64; 4]) -> (f64, Option<usize>) {
+        let bottom = [
+            cube.bottom(channel, &self.reds),
+            cube.bottom(channel, &self.greens),
+            cube.bottom(channel, &self.blues),
+            cube.bottom(channel, &self.weights),
+        ];
+        let mut max = 0.0;
+        let mut cut = None;
+        for position in cube.lower(channel) + 1..cube.upper(channel) {
+            let half = [
+                bottom[0] + cube.top(channel, position, &self.reds),
+                bottom[1] + cube.top(channel, position, &self.greens),
+                bottom[2] + cube.top(channel, position, &self.blues),
+                bottom[3] + cube.top(channel, position, &self.weights),
+            ];
+            if half[3] == 0 {
+                continue;
+            }
+            let rest = [whole[0] - half[0], whole[1] - half[1], whole[2] - half[2], whole[3] - half[3]];
+            if rest[3] == 0 {
+                continue;
+            }
+            let score = |m: &[i64; 4]| {
+                let (r, g, b) = (m[0] as f64, m[1] as f64, m[2] as f64);
+                (r * r + g * g + b * b) / m[3] as f64
+            };
+            let temp = score(&half) + score(&rest);
+            if temp > max {
+                max = temp;
+                cut = Some(position);
+            }
+        }
+        (max, cut)
+    }
+
+    /// Cuts `cube` in two where it most reduces variance, shrinking it to the
+    /// lower half and returning the upper half. Returns `None` if the cube
+    /// can't be cut.
+    fn cut(&self, cube: &mut Cube) -> Option<Cube> {
+        let whole = [
+            cube.volume(&self.reds),
+            cube.volume(&self.greens),
+            cube.volume(&self.blues),
+            cube.volume(&self.weights),
+        ];
+        let (max_r, cut_r) = self.maximize(cube, Channel::Red, whole);
+        let (max_g, cut_g) = self.maximize(cube, Channel::Green, whole);
+        let (max_b, cut_b) = self.maximize(cube, Channel::Blue, whole);
+
+        let (channel, cut) = if max_r >= max_g && max_r >= max_b {
+            (Channel::Red, cut_r?)
+        } else if max_g >= max_r && max_g >= max_b {
+            (Channel::Green, cut_g?)
+        } else {
+            (Channel::Blue, cut_b?)
+        };
+
+        let mut upper = *cube;
+        match channel {
+            Channel::Red => {
+                upper.r0 = cut;
+                cube.r1 = cut;
+            },
+            Channel::Green => {
+                upper.g0 = cut;
+                cube.g1 = cut;
+            },
+            Channel::Blue => {
+                upper.b0 = cut;
+                cube.b1 = cut;
+            },
+        }
+        cube.cells = (cube.r1 - cube.r0) * (cube.g1 - cube.g0) * (cube.b1 - cube.b0);
+        upper.cells = (upper.r1 - upper.r0) * (upper.g1 - upper.g0) * (upper.b1 - upper.b0);
+        Some(upper)
+    }
+
+    /// Computes a palette of at most `max_colors` colors for the histogram.
+    pub fn palette(mut self, max_colors: usize) -> Palette {
+        self.accumulate();
+
+        let max_colors = max_colors.max(1);
+        let mut cubes = vec![Cube { r0: 0, r1: SIDE - 1, g0: 0, g1: SIDE - 1, b0: 0, b1: SIDE - 1, cells: 0 }];
+        let mut variances = vec![0.0];
+        let mut next = 0;
+        while cubes.len() < max_colors {
+            match self.cut(&mut cubes[next]) {
+                Some(upper) => {
+                    variances[next] = if cubes[next].cells > 1 { self.variance(&cubes[next]) } else { 0.0 };
+                    variances.push(if upper.cells > 1 { self.variance(&upper) } else { 0.0 });
+                    cubes.push(upper);
+                },
+                None => variances[next] = 0.0,
+            }
+
+            let (largest, variance) = variances.iter().enumerate()
+                .fold((0, 0.0), |largest, (n, &v)| if v > largest.1 { (n, v) } else { largest });
+            if variance <= 0.0 {
+                break;
+            }
+            next = largest;
+        }
+
+        let mut tags = vec![0u8; SIDE * SIDE * SIDE];
+        let colors = cubes.iter().enumerate().map(|(n, cube)| {
+            for r in cube.r0 + 1..=cube.r1 {
+                for g in cube.g0 + 1..=cube.g1 {
+                    for b in cube.b0 + 1..=cube.b1 {
+                        tags[at(r, g, b)] = n as u8;
+                    }
+                }
+            }
+            let weight = cube.volume(&self.weights);
+            if weight > 0 {
+                let mean = |m: &[i64]| ((cube.volume(m) + weight / 2) / weight) as u8;
+                [mean(&self.reds), mean(&self.greens), mean(&self.blues)]
+            } else {
+                [0, 0, 0]
+            }
+        }).collect();
+
+        Palette { colors, tags }
+    }
+}
+
+/// Colors chosen by the quantizer, and which of them each histogram cell
+/// maps to.
+pub struct Palette {
+    pub colors: Vec<Rgb>,
+    tags: Vec<u8>,
+}
+
+impl Palette {
+    pub fn index_of(&self, color: &Rgb) -> u8 {
+        self.tags[cell(color)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Histogram;
+
+    #[test]
+    fn test_separates_distinct_colors() {
+        let colors = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [240, 240, 240]];
+        let mut histogram = Histogram::new();
+        for color in &colors {
+            for _ in 0..10 {
+                histogram.add(*color);
+            }
+        }
+        let palette = histogram.palette(256);
+        assert_eq!(palette.colors.len(), 4);
+        for color in &colors {
+            assert_eq!(palette.colors[palette.index_of(color) as usize], *color);
+        }
+    }
+
+    #[test]
+    fn test_limits_colors() {
+        let mut histogram = Histogram::new();
+        for n in 0..4096u32 {
+            histogram.add([(n % 16 * 16) as u8, (n / 16 % 16 * 16) as u8, (n / 256 * 16) as u8]);
+        }
+        let palette = histogram.palette(16);
+        assert_eq!(palette.colors.len(), 16);
+        for n in 0..=255u8 {
+            assert!((palette.index_of(&[n, 255 - n, n / 2]) as usize) < 16);
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut left = Histogram::new();
+        let mut right = Histogram::new();
+        let mut both = Histogram::new();
+        for n in 0..200u32 {
+            let color = [(n * 5 % 256) as u8, (n * 11 % 256) as u8, (n * 3 % 256) as u8];
+            if n % 2 == 0 { left.add(color) } else { right.add(color) }
+            both.add(color);
+        }
+        left.merge(&right);
+        assert_eq!(left.palette(32).colors, both.palette(32).colors);
+    }
+}